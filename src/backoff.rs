@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// Exponential backoff between attempts, from `min` doubling up to `max`
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            delay: min,
+        }
+    }
+
+    /// Time to wait before the next attempt, doubled every time up to `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over from `min`, after an attempt succeeded
+    pub fn reset(&mut self) {
+        self.delay = self.min;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(delays: impl Iterator<Item = Duration>) -> Vec<u64> {
+        delays.map(|d| d.as_secs()).collect()
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        let delays = std::iter::repeat_with(|| backoff.next_delay()).take(7);
+        assert_eq!(secs(delays), [5, 10, 20, 40, 60, 60, 60]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        let delays = std::iter::repeat_with(|| backoff.next_delay()).take(2);
+        assert_eq!(secs(delays), [5, 10]);
+    }
+}
@@ -0,0 +1,641 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Result};
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+/// Madrid, the default time zone
+pub const DEFAULT_TIMEZONE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+/// A time zone described by a POSIX TZ string, e.g.
+/// `CET-1CEST,M3.5.0,M10.5.0/3`: the standard time offset and, optionally,
+/// the daylight saving time offset and the rules of when it starts and ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZone {
+    standard: UtcOffset,
+    dst: Option<Dst>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Dst {
+    offset: UtcOffset,
+    /// Given in standard time
+    start: Transition,
+    /// Given in daylight saving time
+    end: Transition,
+}
+
+/// Day and local time of a DST change
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transition {
+    day: TransitionDay,
+    /// Seconds from the local midnight, may be negative or past a day
+    seconds: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransitionDay {
+    /// `Jn`: day 1 to 365 of the year, February 29 is never counted
+    Julian(u16),
+    /// `n`: day 0 to 365 of the year, counting February 29
+    Ordinal(u16),
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` (1 to 5, 5 is the
+    /// last one) of month `m`
+    Weekday { month: u8, week: u8, weekday: u8 },
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        TimeZone::parse(DEFAULT_TIMEZONE).unwrap()
+    }
+}
+
+impl TimeZone {
+    pub fn parse(tz: &str) -> Result<TimeZone> {
+        let mut input = Input(tz.trim());
+
+        input.name()?;
+        let standard = input.offset()?;
+
+        if input.is_empty() {
+            return Ok(TimeZone {
+                standard,
+                dst: None,
+            });
+        }
+
+        input.name()?;
+        // One hour ahead of standard time unless given
+        let offset = if input.peek().map_or(false, |c| c != ',') {
+            input.offset()?
+        } else {
+            UtcOffset::from_whole_seconds(standard.whole_seconds() + 3600)?
+        };
+
+        // POSIX falls back to the US rules when there are none, ask for them
+        input.expect(',')?;
+        let start = input.transition()?;
+        input.expect(',')?;
+        let end = input.transition()?;
+
+        if !input.is_empty() {
+            bail!("Unexpected \"{}\" in time zone \"{}\"", input.0, tz);
+        }
+
+        Ok(TimeZone {
+            standard,
+            dst: Some(Dst { offset, start, end }),
+        })
+    }
+
+    /// Offset from UTC in effect at `time`
+    pub fn offset_at(&self, time: OffsetDateTime) -> UtcOffset {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return self.standard,
+        };
+
+        let year = time.to_offset(self.standard).year();
+        let start = dst.start.at(year, self.standard);
+        let end = dst.end.at(year, dst.offset);
+
+        let in_dst = if start <= end {
+            start <= time && time < end
+        } else {
+            // Southern hemisphere, DST over the new year
+            time < end || start <= time
+        };
+
+        if in_dst {
+            dst.offset
+        } else {
+            self.standard
+        }
+    }
+
+    /// `time` in local time
+    pub fn to_local(&self, time: OffsetDateTime) -> OffsetDateTime {
+        time.to_offset(self.offset_at(time))
+    }
+
+    /// The current local time
+    pub fn now(&self) -> OffsetDateTime {
+        self.to_local(OffsetDateTime::now_utc())
+    }
+
+    /// The instant of the local `time`. A time skipped by the change to DST
+    /// is taken in standard time, a repeated one as the first of both.
+    pub fn instant(&self, time: PrimitiveDateTime) -> OffsetDateTime {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return time.assume_offset(self.standard),
+        };
+
+        let daylight = time.assume_offset(dst.offset);
+        if self.offset_at(daylight) == dst.offset {
+            daylight
+        } else {
+            time.assume_offset(self.standard)
+        }
+    }
+}
+
+impl Transition {
+    /// When the transition happens in `year`, `offset` being the one in effect
+    /// until then
+    fn at(&self, year: i32, offset: UtcOffset) -> OffsetDateTime {
+        let date = self.day.date(year);
+        date.midnight().assume_offset(offset) + Duration::seconds(self.seconds)
+    }
+}
+
+impl TransitionDay {
+    fn date(&self, year: i32) -> Date {
+        let first = Date::from_ordinal_date(year, 1).unwrap();
+        match *self {
+            TransitionDay::Julian(day) => {
+                let leap_day = time::util::is_leap_year(year) && day >= 60;
+                first + Duration::days(day as i64 - 1 + leap_day as i64)
+            }
+            TransitionDay::Ordinal(day) => {
+                let days = time::util::days_in_year(year) as i64;
+                first + Duration::days((day as i64).min(days - 1))
+            }
+            TransitionDay::Weekday {
+                month,
+                week,
+                weekday,
+            } => {
+                let month = Month::try_from(month).unwrap();
+                let first = Date::from_calendar_date(year, month, 1).unwrap();
+                let first_weekday = first.weekday().number_days_from_sunday() as i64;
+                let day = (weekday as i64 - first_weekday).rem_euclid(7) + (week as i64 - 1) * 7;
+
+                let mut date = first + Duration::days(day);
+                // Week 5 is the last one, there may be only four
+                while date.month() != month {
+                    date -= Duration::days(7);
+                }
+                date
+            }
+        }
+    }
+}
+
+/// What is left to parse of a TZ string
+struct Input<'a>(&'a str);
+
+impl<'a> Input<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.0.chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.0.strip_prefix(c) {
+            Some(rest) => {
+                self.0 = rest;
+                Ok(())
+            }
+            None => bail!("Expected '{}' at \"{}\"", c, self.0),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let end = self.0.find(|c| !f(c)).unwrap_or(self.0.len());
+        let (taken, rest) = self.0.split_at(end);
+        self.0 = rest;
+        taken
+    }
+
+    /// A zone name, either letters (`CET`) or quoted (`<+03>`)
+    fn name(&mut self) -> Result<&'a str> {
+        let name = if self.peek() == Some('<') {
+            self.expect('<')?;
+            let name = self.take_while(|c| c != '>');
+            self.expect('>')?;
+            name
+        } else {
+            self.take_while(|c| c.is_ascii_alphabetic())
+        };
+
+        if name.len() < 3 {
+            bail!("Invalid time zone name \"{}\"", name);
+        }
+        Ok(name)
+    }
+
+    /// `[+-]hh[:mm[:ss]]`
+    fn seconds(&mut self) -> Result<i64> {
+        let sign = match self.peek() {
+            Some('-') => -1,
+            Some('+') => 1,
+            _ => 0,
+        };
+        if sign != 0 {
+            self.0 = &self.0[1..];
+        }
+
+        let mut seconds = 0;
+        for (idx, unit) in [3600, 60, 1].iter().enumerate() {
+            if idx > 0 {
+                if self.peek() != Some(':') {
+                    break;
+                }
+                self.expect(':')?;
+            }
+            let digits = self.take_while(|c| c.is_ascii_digit());
+            let value: i64 = digits
+                .parse()
+                .map_err(|_| anyhow!("Invalid time at \"{}\"", self.0))?;
+            seconds += value * unit;
+        }
+
+        Ok(if sign < 0 { -seconds } else { seconds })
+    }
+
+    /// POSIX offsets are the time to add to get UTC, west of Greenwich is
+    /// positive
+    fn offset(&mut self) -> Result<UtcOffset> {
+        let seconds = self.seconds()?;
+        Ok(UtcOffset::from_whole_seconds(-seconds as i32)?)
+    }
+
+    fn number(&mut self) -> Result<u16> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        digits
+            .parse()
+            .map_err(|_| anyhow!("Expected a number at \"{}\"", self.0))
+    }
+
+    /// `date[/time]`, at 02:00 when the time is not given
+    fn transition(&mut self) -> Result<Transition> {
+        let day = match self.peek() {
+            Some('J') => {
+                self.expect('J')?;
+                match self.number()? {
+                    day @ 1..=365 => TransitionDay::Julian(day),
+                    day => bail!("Invalid Julian day {}", day),
+                }
+            }
+            Some('M') => {
+                self.expect('M')?;
+                let month = self.number()?;
+                self.expect('.')?;
+                let week = self.number()?;
+                self.expect('.')?;
+                let weekday = self.number()?;
+                if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                    bail!("Invalid date M{}.{}.{}", month, week, weekday);
+                }
+                TransitionDay::Weekday {
+                    month: month as u8,
+                    week: week as u8,
+                    weekday: weekday as u8,
+                }
+            }
+            _ => match self.number()? {
+                day @ 0..=365 => TransitionDay::Ordinal(day),
+                day => bail!("Invalid day {}", day),
+            },
+        };
+
+        let seconds = if self.peek() == Some('/') {
+            self.expect('/')?;
+            self.seconds()?
+        } else {
+            2 * 3600
+        };
+
+        Ok(Transition { day, seconds })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Time;
+
+    use super::*;
+
+    /// Sydney, DST from October to April
+    const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+
+    fn date(year: i32, month: u8, day: u8) -> Date {
+        Date::from_calendar_date(year, Month::try_from(month).unwrap(), day).unwrap()
+    }
+
+    fn datetime(year: i32, month: u8, day: u8, hour: u8, minute: u8) -> PrimitiveDateTime {
+        date(year, month, day).with_time(Time::from_hms(hour, minute, 0).unwrap())
+    }
+
+    fn utc(year: i32, month: u8, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        datetime(year, month, day, hour, minute).assume_utc()
+    }
+
+    fn hours(hours: i8) -> UtcOffset {
+        UtcOffset::from_hms(hours, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn madrid_changes_on_the_last_sundays_of_march_and_october() {
+        let madrid = TimeZone::default();
+
+        // 2023-03-26 02:00 CET and 2023-10-29 03:00 CEST
+        assert_eq!(madrid.offset_at(utc(2023, 3, 26, 0, 59)), hours(1));
+        assert_eq!(madrid.offset_at(utc(2023, 3, 26, 1, 0)), hours(2));
+        assert_eq!(madrid.offset_at(utc(2023, 10, 29, 0, 59)), hours(2));
+        assert_eq!(madrid.offset_at(utc(2023, 10, 29, 1, 0)), hours(1));
+
+        // March 2024 has five Sundays, October 2024 only four
+        assert_eq!(madrid.offset_at(utc(2024, 3, 31, 1, 0)), hours(2));
+        assert_eq!(madrid.offset_at(utc(2024, 3, 24, 1, 0)), hours(1));
+        assert_eq!(madrid.offset_at(utc(2024, 10, 27, 1, 0)), hours(1));
+        assert_eq!(madrid.offset_at(utc(2024, 10, 20, 1, 0)), hours(2));
+
+        assert_eq!(
+            madrid.to_local(utc(2023, 7, 1, 10, 0)),
+            datetime(2023, 7, 1, 12, 0).assume_offset(hours(2))
+        );
+    }
+
+    #[test]
+    fn skipped_hour_is_taken_in_standard_time() {
+        let madrid = TimeZone::default();
+
+        // 02:30 never happens, it is 03:30 CEST
+        assert_eq!(
+            madrid.instant(datetime(2023, 3, 26, 2, 30)),
+            utc(2023, 3, 26, 1, 30)
+        );
+        assert_eq!(
+            madrid.instant(datetime(2023, 3, 26, 3, 0)),
+            utc(2023, 3, 26, 1, 0)
+        );
+    }
+
+    #[test]
+    fn repeated_hour_is_taken_the_first_time() {
+        let madrid = TimeZone::default();
+
+        // 02:30 happens in CEST and then again in CET
+        assert_eq!(
+            madrid.instant(datetime(2023, 10, 29, 2, 30)),
+            utc(2023, 10, 29, 0, 30)
+        );
+        assert_eq!(
+            madrid.instant(datetime(2023, 10, 29, 3, 0)),
+            utc(2023, 10, 29, 2, 0)
+        );
+    }
+
+    #[test]
+    fn southern_dst_spans_the_new_year() {
+        let sydney = TimeZone::parse(SYDNEY).unwrap();
+
+        // Ends 2023-04-02 03:00 AEDT, starts again 2023-10-01 02:00 AEST
+        assert_eq!(sydney.offset_at(utc(2023, 1, 15, 0, 0)), hours(11));
+        assert_eq!(sydney.offset_at(utc(2023, 4, 1, 15, 59)), hours(11));
+        assert_eq!(sydney.offset_at(utc(2023, 4, 1, 16, 0)), hours(10));
+        assert_eq!(sydney.offset_at(utc(2023, 7, 1, 0, 0)), hours(10));
+        assert_eq!(sydney.offset_at(utc(2023, 9, 30, 15, 59)), hours(10));
+        assert_eq!(sydney.offset_at(utc(2023, 9, 30, 16, 0)), hours(11));
+        assert_eq!(sydney.offset_at(utc(2023, 12, 31, 23, 0)), hours(11));
+    }
+
+    #[test]
+    fn julian_days_never_count_february_29() {
+        let day = |tz: &str| TimeZone::parse(tz).unwrap().dst.unwrap().start.day;
+
+        let march_1 = day("CET-1CEST,J60,J300");
+        assert_eq!(march_1.date(2023), date(2023, 3, 1));
+        assert_eq!(march_1.date(2024), date(2024, 3, 1));
+        assert_eq!(day("CET-1CEST,J365,J300").date(2024), date(2024, 12, 31));
+    }
+
+    #[test]
+    fn zero_based_days_count_february_29() {
+        let day = |tz: &str| TimeZone::parse(tz).unwrap().dst.unwrap().start.day;
+
+        let day_59 = day("CET-1CEST,59,300");
+        assert_eq!(day_59.date(2023), date(2023, 3, 1));
+        assert_eq!(day_59.date(2024), date(2024, 2, 29));
+        assert_eq!(day("CET-1CEST,0,300").date(2023), date(2023, 1, 1));
+        assert_eq!(day("CET-1CEST,365,300").date(2023), date(2023, 12, 31));
+    }
+
+    #[test]
+    fn day_rules_change_at_their_time() {
+        // From day 100 at 01:00 to day 200 at 02:00 (the default) in 2023
+        let tz = TimeZone::parse("<+03>-3<+04>,100/1,200").unwrap();
+
+        assert_eq!(tz.offset_at(utc(2023, 4, 10, 21, 59)), hours(3));
+        assert_eq!(tz.offset_at(utc(2023, 4, 10, 22, 0)), hours(4));
+        assert_eq!(tz.offset_at(utc(2023, 7, 19, 21, 59)), hours(4));
+        assert_eq!(tz.offset_at(utc(2023, 7, 19, 22, 0)), hours(3));
+    }
+
+    #[test]
+    fn offsets_and_times_are_parsed() {
+        let tz = TimeZone::parse("NST3:30NDT,M3.2.0/0:01,M11.1.0/-1").unwrap();
+        let dst = tz.dst.as_ref().unwrap();
+
+        assert_eq!(tz.standard, UtcOffset::from_hms(-3, -30, 0).unwrap());
+        assert_eq!(dst.offset, UtcOffset::from_hms(-2, -30, 0).unwrap());
+        assert_eq!(dst.start.seconds, 60);
+        assert_eq!(dst.end.seconds, -3600);
+
+        let utc_only = TimeZone::parse("UTC0").unwrap();
+        assert_eq!(utc_only.offset_at(utc(2023, 7, 1, 0, 0)), hours(0));
+    }
+
+    #[test]
+    fn malformed_time_zones_are_rejected() {
+        for tz in [
+            "",
+            "CE-1",
+            "CET",
+            "CET+x",
+            "<+03-3",
+            "CET-1CEST",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST;M3.5.0,M10.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+            "CET-1CEST,M3.6.0,M10.5.0",
+            "CET-1CEST,M3.5.7,M10.5.0",
+            "CET-1CEST,M3.5,M10.5.0",
+            "CET-1CEST,J0,J300",
+            "CET-1CEST,J366,J300",
+            "CET-1CEST,366,300",
+            "CET-1CEST,M3.5.0,M10.5.0/3x",
+            "CET-1CEST,M3.5.0/,M10.5.0",
+        ] {
+            assert!(TimeZone::parse(tz).is_err(), "\"{}\" was accepted", tz);
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::sync::TimeSync;
+
+#[cfg(target_os = "espidf")]
+mod sync {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+    use log::*;
+    use time::OffsetDateTime;
+
+    use crate::peripherals::display::DisplayMessage;
+
+    /// Anything earlier means the clock was never set (2023-01-01)
+    const MIN_VALID_TIME: i64 = 1672531200;
+    /// The clock drifts, it isn't trusted longer than this after a sync
+    const TRUST_SECS: i64 = 24 * 60 * 60;
+    /// How often the sync status is checked and the time saved
+    const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Written to `TIME_SAVED` along with the times, whatever else is there
+    /// is left from before a power loss
+    const TIME_MAGIC: u32 = 0x7153_a7ed;
+
+    // Kept in RTC memory that is not initialized on boot, so it survives deep
+    // sleep and resets. Only valid while `TIME_SAVED` holds `TIME_MAGIC`.
+    #[link_section = ".rtc_noinit"]
+    static mut TIME_SAVED: u32 = 0;
+    #[link_section = ".rtc_noinit"]
+    static mut LAST_SYNC: i64 = 0;
+    #[link_section = ".rtc_noinit"]
+    static mut LAST_TIME: i64 = 0;
+
+    fn save_time(now: i64, synced: bool) {
+        // Only written at start and then by the checker thread
+        unsafe {
+            if TIME_SAVED != TIME_MAGIC {
+                LAST_SYNC = 0;
+            }
+            LAST_TIME = now;
+            if synced {
+                LAST_SYNC = now;
+            }
+            TIME_SAVED = TIME_MAGIC;
+        }
+    }
+
+    /// The last sync and the last time saved, none after a power loss
+    fn saved_times() -> Option<(i64, i64)> {
+        unsafe { (TIME_SAVED == TIME_MAGIC).then(|| (LAST_SYNC, LAST_TIME)) }
+    }
+
+    fn unix_now() -> i64 {
+        OffsetDateTime::now_utc().unix_timestamp()
+    }
+
+    /// Keeps the clock set with SNTP. ESP-IDF syncs again on its own every
+    /// hour, the time is trusted while the last sync is recent enough.
+    pub struct TimeSync {
+        _sntp: EspSntp,
+        synced: Arc<AtomicBool>,
+    }
+
+    impl TimeSync {
+        /// Starts SNTP and waits up to `timeout` for the first sync. On
+        /// timeout the clock keeps the time it had across deep sleep, or the
+        /// last one saved in RTC memory, marked as not synced.
+        pub fn start(timeout: Duration, display: mpsc::SyncSender<DisplayMessage>) -> Result<Self> {
+            restore_time();
+
+            let sntp = EspSntp::new_default()?;
+            let started = Instant::now();
+            let mut completed = false;
+            while started.elapsed() < timeout {
+                if sntp.get_sync_status() == SyncStatus::Completed {
+                    completed = true;
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            let now = unix_now();
+            if completed {
+                info!("Time synced via SNTP");
+                save_time(now, true);
+            } else {
+                warn!("No SNTP reply in {}s", timeout.as_secs());
+            }
+
+            let synced = Arc::new(AtomicBool::new(is_trusted(now)));
+            display.send(DisplayMessage::TimeSynced(synced.load(Ordering::Relaxed)))?;
+
+            let checked = synced.clone();
+            thread::Builder::new()
+                .stack_size(4096)
+                .spawn(move || loop {
+                    thread::sleep(CHECK_INTERVAL);
+
+                    let now = unix_now();
+                    let completed = sntp_completed();
+                    if completed {
+                        info!("Time synced via SNTP");
+                    }
+                    save_time(now, completed);
+
+                    let trusted = is_trusted(now);
+                    if checked.swap(trusted, Ordering::Relaxed) != trusted
+                        && display.send(DisplayMessage::TimeSynced(trusted)).is_err()
+                    {
+                        break;
+                    }
+                })?;
+
+            Ok(TimeSync {
+                _sntp: sntp,
+                synced,
+            })
+        }
+
+        /// Whether the clock was synced recently enough to be trusted
+        pub fn is_synced(&self) -> bool {
+            self.synced.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Whether a sync completed since the last call, ESP-IDF resets the
+    /// status once read
+    fn sntp_completed() -> bool {
+        let status = unsafe { esp_idf_sys::sntp_get_sync_status() };
+        status == esp_idf_sys::sntp_sync_status_t_SNTP_SYNC_STATUS_COMPLETED
+    }
+
+    fn is_trusted(now: i64) -> bool {
+        let last_sync = saved_times().map_or(0, |(last_sync, _)| last_sync);
+        last_sync >= MIN_VALID_TIME && now >= last_sync && now - last_sync < TRUST_SECS
+    }
+
+    /// Sets the clock to the last time saved in RTC memory when it was lost,
+    /// e.g. after a reset. Better late than 1970, but not trusted.
+    fn restore_time() {
+        let last_time = match saved_times() {
+            Some((_, last_time)) if last_time >= MIN_VALID_TIME => last_time,
+            _ => return,
+        };
+        if unix_now() >= MIN_VALID_TIME {
+            return;
+        }
+
+        info!("Clock not set, restoring the last known time");
+        let tv = esp_idf_sys::timeval {
+            tv_sec: last_time as _,
+            tv_usec: 0,
+        };
+        unsafe {
+            esp_idf_sys::settimeofday(&tv, std::ptr::null());
+            LAST_SYNC = 0;
+        }
+    }
+}
@@ -0,0 +1,751 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::clock::{TimeZone, DEFAULT_TIMEZONE};
+use crate::emtmadrid::transport::DEFAULT_MAX_BODY_SIZE;
+use crate::schedule::Schedule;
+
+/// Current schema version of the stored configuration, bump it and add a step
+/// to `migrate` whenever `Config` changes in an incompatible way.
+pub const CONFIG_VERSION: u32 = 4;
+
+// Compile time values, only used as defaults on first boot
+const DEFAULT_WIFI_SSID: Option<&str> = option_env!("RUST_ESP32_STD_DEMO_WIFI_SSID");
+const DEFAULT_WIFI_PASS: Option<&str> = option_env!("RUST_ESP32_STD_DEMO_WIFI_PASS");
+const DEFAULT_EMT_USER: Option<&str> = option_env!("EMT_USER");
+const DEFAULT_EMT_PASS: Option<&str> = option_env!("EMT_PASS");
+
+const SECONDS_TO_874: u32 = 5 * 60;
+const SECONDS_TO_1455: u32 = 3 * 60;
+
+/// The stop of each line of the v1 defaults, v1 did not store it
+const V1_LINE_STOPS: [(&str, &str); 6] = [
+    ("31", "874"),
+    ("33", "874"),
+    ("36", "874"),
+    ("39", "874"),
+    ("65", "874"),
+    ("138", "1455"),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    pub version: u32,
+    pub networks: Vec<NetworkConfig>,
+    pub emt_user: String,
+    pub emt_pass: String,
+    /// Largest EMT API response accepted, busy stops need more than a few KB
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: u32,
+    pub stops: Vec<StopConfig>,
+    pub destinations: Vec<DestinationConfig>,
+    pub lines: Vec<LineConfig>,
+    /// POSIX TZ rules of the local time, see `clock::TimeZone`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default)]
+    pub refresh: RefreshConfig,
+    #[serde(default)]
+    pub battery: BatteryConfig,
+    #[serde(default)]
+    pub button: ButtonConfig,
+    #[serde(default)]
+    pub polling: PollingConfig,
+    /// Times of the week the monitor is active, it sleeps between them. Always
+    /// active when empty.
+    #[serde(default = "default_schedule")]
+    pub schedule: Vec<WindowConfig>,
+}
+
+/// A saved Wi-Fi network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub ssid: String,
+    pub pass: String,
+    /// Networks in range with a higher priority are joined first, the
+    /// strongest signal wins between equal ones
+    #[serde(default)]
+    pub priority: u32,
+}
+
+/// A time of the week the monitor is active
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowConfig {
+    /// Comma separated days or ranges of days, e.g. "mon-fri" or "sat,sun"
+    pub days: String,
+    /// Local time, "HH:MM"
+    pub start: String,
+    /// Local time, "HH:MM", on the same day as `start`
+    pub end: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopConfig {
+    /// EMT stop number
+    pub id: String,
+    /// Short name shown on the display instead of the stop number
+    pub label: String,
+    /// Time needed to walk from home to the stop
+    pub seconds_from_home: u32,
+}
+
+/// A place reachable by bus, it gets its own ETA column on the display
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    pub name: String,
+    /// Name of a built-in icon for the header ("school", "work", "bus"), the
+    /// destination name is shown instead when empty or unknown
+    #[serde(default)]
+    pub icon: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineConfig {
+    pub name: String,
+    /// Ride time from the stop to each destination served by the line
+    pub seconds_to: BTreeMap<String, u32>,
+}
+
+/// When the e-ink panel gets a slow full refresh instead of a quick one, quick
+/// refreshes are faster but leave ghosting behind. Zero disables a trigger.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RefreshConfig {
+    /// Full refresh after this many quick ones
+    pub full_every_updates: u32,
+    /// Full refresh when the last one is older than this
+    pub full_every_minutes: u32,
+    /// Full refresh when at least this percentage of the screen changed
+    pub full_above_changed_percent: u32,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        RefreshConfig {
+            full_every_updates: 50,
+            full_every_minutes: 15,
+            full_above_changed_percent: 40,
+        }
+    }
+}
+
+/// How the battery voltage is read and turned into a charge estimate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatteryConfig {
+    /// Ratio of the voltage divider between the battery and the ADC pin
+    pub divider: f32,
+    /// Discharge curve of the cell, volts and percent of charge left, by
+    /// increasing voltage
+    pub curve: Vec<(f32, f32)>,
+    /// Readings above this come from the USB supply rather than the cell
+    pub usb_volts: f32,
+    /// Time between readings
+    pub interval_secs: u32,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        // A typical Li-ion cell under a light load
+        BatteryConfig {
+            divider: 2.0,
+            curve: vec![
+                (3.30, 0.0),
+                (3.50, 5.0),
+                (3.60, 10.0),
+                (3.70, 25.0),
+                (3.75, 40.0),
+                (3.80, 55.0),
+                (3.85, 65.0),
+                (3.95, 80.0),
+                (4.05, 90.0),
+                (4.20, 100.0),
+            ],
+            usb_volts: 4.35,
+            interval_secs: 60,
+        }
+    }
+}
+
+/// How often the arrivals are fetched while active: often when a bus has to
+/// be caught soon, to follow it, and rarely otherwise, to save API calls and
+/// radio time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollingConfig {
+    /// Time between fetches when a bus has to be caught soon
+    pub fast_secs: u32,
+    /// Longest time between fetches
+    pub slow_secs: u32,
+    /// A bus has to be caught soon when it is time to leave home for it
+    /// within this time
+    pub soon_secs: u32,
+    /// Time between redraws of the countdowns, counted down locally between
+    /// fetches
+    pub redraw_secs: u32,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            fast_secs: 10,
+            slow_secs: 60,
+            soon_secs: 5 * 60,
+            redraw_secs: 10,
+        }
+    }
+}
+
+/// The button on GPIO0, a press also wakes the device outside the schedule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ButtonConfig {
+    /// Time the monitor stays active after a press outside the schedule
+    pub on_demand_minutes: u32,
+    /// Holding the button this long makes a long press
+    pub long_press_ms: u32,
+    pub long_press: LongPressAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LongPressAction {
+    /// Restarts into the setup portal
+    Setup,
+    /// Redraws the e-ink panel with a full refresh
+    FullRefresh,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        ButtonConfig {
+            on_demand_minutes: 5,
+            long_press_ms: 2000,
+            long_press: LongPressAction::FullRefresh,
+        }
+    }
+}
+
+impl StopConfig {
+    fn new(id: &str, seconds_from_home: u32) -> Self {
+        StopConfig {
+            id: id.to_string(),
+            label: id.to_string(),
+            seconds_from_home,
+        }
+    }
+}
+
+impl DestinationConfig {
+    fn new(name: &str, icon: &str) -> Self {
+        DestinationConfig {
+            name: name.to_string(),
+            icon: icon.to_string(),
+        }
+    }
+}
+
+impl WindowConfig {
+    fn new(days: &str, start: &str, end: &str) -> Self {
+        WindowConfig {
+            days: days.to_string(),
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+}
+
+impl LineConfig {
+    fn new(name: &str, seconds_to: &[(&str, u32)]) -> Self {
+        LineConfig {
+            name: name.to_string(),
+            seconds_to: seconds_to
+                .iter()
+                .map(|(destination, secs)| (destination.to_string(), *secs))
+                .collect(),
+        }
+    }
+
+    pub fn seconds_to(&self, destination: &str) -> Option<u32> {
+        self.seconds_to.get(destination).copied()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            networks: match DEFAULT_WIFI_SSID {
+                Some(ssid) if !ssid.is_empty() => vec![NetworkConfig {
+                    ssid: ssid.to_string(),
+                    pass: DEFAULT_WIFI_PASS.unwrap_or("").to_string(),
+                    priority: 0,
+                }],
+                _ => Vec::new(),
+            },
+            emt_user: DEFAULT_EMT_USER.unwrap_or("").to_string(),
+            emt_pass: DEFAULT_EMT_PASS.unwrap_or("").to_string(),
+            max_response_bytes: default_max_response_bytes(),
+            stops: vec![
+                StopConfig::new("874", SECONDS_TO_874),
+                StopConfig::new("1455", SECONDS_TO_1455),
+            ],
+            destinations: vec![
+                DestinationConfig::new("School", "school"),
+                DestinationConfig::new("Work", "work"),
+            ],
+            lines: vec![
+                LineConfig::new("31", &[("School", (4 + 4) * 60), ("Work", (8 + 8) * 60)]),
+                LineConfig::new("33", &[("School", (5 + 6) * 60)]),
+                LineConfig::new("36", &[("School", (5 + 1) * 60)]),
+                LineConfig::new("39", &[("School", (5 + 6) * 60), ("Work", (7 + 7) * 60)]),
+                LineConfig::new("65", &[("School", (4 + 4) * 60), ("Work", (8 + 8) * 60)]),
+                LineConfig::new("138", &[("School", (6 + 6) * 60), ("Work", (12 + 7) * 60)]),
+            ],
+            timezone: default_timezone(),
+            refresh: RefreshConfig::default(),
+            battery: BatteryConfig::default(),
+            button: ButtonConfig::default(),
+            polling: PollingConfig::default(),
+            schedule: default_schedule(),
+        }
+    }
+}
+
+fn default_max_response_bytes() -> u32 {
+    DEFAULT_MAX_BODY_SIZE as u32
+}
+
+fn default_timezone() -> String {
+    DEFAULT_TIMEZONE.to_string()
+}
+
+/// School and lunch time on weekdays
+fn default_schedule() -> Vec<WindowConfig> {
+    vec![
+        WindowConfig::new("mon-fri", "07:30", "09:00"),
+        WindowConfig::new("mon-fri", "13:30", "15:00"),
+    ]
+}
+
+impl Config {
+    pub fn stop(&self, id: &str) -> Option<&StopConfig> {
+        self.stops.iter().find(|s| s.id == id)
+    }
+
+    pub fn line(&self, name: &str) -> Option<&LineConfig> {
+        self.lines.iter().find(|l| l.name == name)
+    }
+
+    /// The configured time zone, the default one if it can't be parsed
+    pub fn timezone(&self) -> TimeZone {
+        TimeZone::parse(&self.timezone).unwrap_or_else(|e| {
+            warn!(
+                "Invalid time zone \"{}\", using the default: {}",
+                self.timezone, e
+            );
+            TimeZone::default()
+        })
+    }
+
+    /// The configured schedule, always active if it can't be parsed
+    pub fn schedule(&self) -> Schedule {
+        Schedule::new(&self.schedule, self.timezone()).unwrap_or_else(|e| {
+            warn!("Invalid schedule, staying always active: {}", e);
+            Schedule::new(&[], self.timezone()).unwrap()
+        })
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Parses a stored configuration, upgrading it from older schema versions
+    pub fn from_json(data: &[u8]) -> Result<Config> {
+        let value: Value = serde_json::from_slice(data)?;
+        Ok(serde_json::from_value(migrate(value)?)?)
+    }
+}
+
+/// Upgrades a stored configuration one schema version at a time
+fn migrate(mut value: Value) -> Result<Value> {
+    let mut version = value["version"].as_u64().unwrap_or(0) as u32;
+
+    if version > CONFIG_VERSION {
+        bail!(
+            "Stored configuration version {} is newer than supported {}",
+            version,
+            CONFIG_VERSION
+        );
+    }
+
+    while version < CONFIG_VERSION {
+        info!("Migrating configuration from version {}", version);
+        value = upgrade(version, value)?;
+        version += 1;
+        value["version"] = version.into();
+    }
+
+    Ok(value)
+}
+
+/// Converts a configuration of schema `version` into `version + 1`
+fn upgrade(version: u32, mut value: Value) -> Result<Value> {
+    match version {
+        1 => {
+            // v1 kept the walking time in every line and stops as plain ids.
+            // A stop takes the walking time of the lines known to stop there,
+            // the longest of all lines for the rest: better to give up on a
+            // bus than to miss it.
+            let mut stop_seconds = BTreeMap::<&str, u64>::new();
+            let mut longest = 0;
+            if let Value::Array(lines) = &mut value["lines"] {
+                for line in lines.iter_mut() {
+                    if let Value::Object(line) = line {
+                        let secs = line.remove("seconds_from_home");
+                        let secs = secs.and_then(|s| s.as_u64()).unwrap_or(0);
+                        longest = longest.max(secs);
+
+                        let name = line.get("name").and_then(Value::as_str);
+                        if let Some((_, stop)) =
+                            V1_LINE_STOPS.iter().find(|(l, _)| Some(*l) == name)
+                        {
+                            let stop_secs = stop_seconds.entry(stop).or_insert(0);
+                            *stop_secs = (*stop_secs).max(secs);
+                        }
+                    }
+                }
+            }
+
+            let stops: Vec<Value> = match &value["stops"] {
+                Value::Array(ids) => ids
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|id| {
+                        serde_json::json!({
+                            "id": id,
+                            "label": id,
+                            "seconds_from_home": stop_seconds.get(id).copied().unwrap_or(longest),
+                        })
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            value["stops"] = Value::Array(stops);
+
+            Ok(value)
+        }
+        2 => {
+            // v2 had fixed School and Work columns, a zero time meant the line
+            // does not go there
+            value["destinations"] = serde_json::json!([
+                { "name": "School", "icon": "school" },
+                { "name": "Work", "icon": "work" },
+            ]);
+
+            if let Value::Array(lines) = &mut value["lines"] {
+                for line in lines.iter_mut() {
+                    if let Value::Object(line) = line {
+                        let mut seconds_to = serde_json::Map::new();
+                        for (field, destination) in
+                            [("seconds_to_school", "School"), ("seconds_to_work", "Work")]
+                        {
+                            match line.remove(field).and_then(|s| s.as_u64()) {
+                                Some(secs) if secs != 0 => {
+                                    seconds_to.insert(destination.to_string(), secs.into());
+                                }
+                                _ => {}
+                            }
+                        }
+                        line.insert("seconds_to".to_string(), Value::Object(seconds_to));
+                    }
+                }
+            }
+
+            Ok(value)
+        }
+        3 => {
+            // v3 had a single Wi-Fi network
+            let mut networks = Vec::new();
+            if let Value::Object(config) = &mut value {
+                let ssid = config.remove("wifi_ssid");
+                let pass = config.remove("wifi_pass");
+                match ssid.as_ref().and_then(Value::as_str) {
+                    Some(ssid) if !ssid.is_empty() => networks.push(serde_json::json!({
+                        "ssid": ssid,
+                        "pass": pass.as_ref().and_then(Value::as_str).unwrap_or(""),
+                        "priority": 0,
+                    })),
+                    _ => {}
+                }
+            }
+            value["networks"] = Value::Array(networks);
+
+            Ok(value)
+        }
+        _ => bail!("Can't migrate configuration version {}", version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Lines of the defaults in the v1 and v2 schemas
+    fn v1_lines() -> Value {
+        json!([
+            { "name": "31", "seconds_from_home": 300, "seconds_to_school": 480, "seconds_to_work": 960 },
+            { "name": "33", "seconds_from_home": 300, "seconds_to_school": 660, "seconds_to_work": 0 },
+            { "name": "36", "seconds_from_home": 300, "seconds_to_school": 360, "seconds_to_work": 0 },
+            { "name": "39", "seconds_from_home": 300, "seconds_to_school": 660, "seconds_to_work": 840 },
+            { "name": "65", "seconds_from_home": 300, "seconds_to_school": 480, "seconds_to_work": 960 },
+            { "name": "138", "seconds_from_home": 180, "seconds_to_school": 720, "seconds_to_work": 1140 },
+        ])
+    }
+
+    fn v2_lines() -> Value {
+        let mut lines = v1_lines();
+        for line in lines.as_array_mut().unwrap() {
+            line.as_object_mut().unwrap().remove("seconds_from_home");
+        }
+        lines
+    }
+
+    /// What every old configuration below migrates to
+    fn expected() -> Config {
+        Config {
+            networks: vec![NetworkConfig {
+                ssid: "home".to_string(),
+                pass: "secret".to_string(),
+                priority: 0,
+            }],
+            emt_user: "user@example.com".to_string(),
+            emt_pass: "emt-secret".to_string(),
+            stops: vec![
+                StopConfig {
+                    id: "874".to_string(),
+                    label: "Home".to_string(),
+                    seconds_from_home: 300,
+                },
+                StopConfig {
+                    id: "1455".to_string(),
+                    label: "Park".to_string(),
+                    seconds_from_home: 180,
+                },
+            ],
+            ..Config::default()
+        }
+    }
+
+    fn load(value: Value) -> Result<Config> {
+        Config::from_json(&serde_json::to_vec(&value).unwrap())
+    }
+
+    #[test]
+    fn v1_is_migrated() {
+        let config = load(json!({
+            "version": 1,
+            "wifi_ssid": "home",
+            "wifi_pass": "secret",
+            "emt_user": "user@example.com",
+            "emt_pass": "emt-secret",
+            "stops": ["874", "1455"],
+            "lines": v1_lines(),
+        }))
+        .unwrap();
+
+        // Stops had no labels, they are shown by number
+        let labels: Vec<&str> = config.stops.iter().map(|s| s.label.as_str()).collect();
+        assert_eq!(labels, ["874", "1455"]);
+        // Each stop keeps the walking time of its own lines
+        let walks: Vec<(&str, u32)> = config
+            .stops
+            .iter()
+            .map(|s| (s.id.as_str(), s.seconds_from_home))
+            .collect();
+        assert_eq!(walks, [("874", 300), ("1455", 180)]);
+        assert_eq!(
+            Config {
+                stops: expected().stops,
+                ..config
+            },
+            expected()
+        );
+    }
+
+    #[test]
+    fn v2_is_migrated() {
+        let config = load(json!({
+            "version": 2,
+            "wifi_ssid": "home",
+            "wifi_pass": "secret",
+            "emt_user": "user@example.com",
+            "emt_pass": "emt-secret",
+            "stops": [
+                { "id": "874", "label": "Home", "seconds_from_home": 300 },
+                { "id": "1455", "label": "Park", "seconds_from_home": 180 },
+            ],
+            "lines": v2_lines(),
+        }))
+        .unwrap();
+
+        assert_eq!(config, expected());
+        // A zero time was a line not going there
+        assert_eq!(config.line("33").unwrap().seconds_to("Work"), None);
+    }
+
+    #[test]
+    fn v3_is_migrated() {
+        let mut v3 = serde_json::to_value(expected()).unwrap();
+        let v3_fields = v3.as_object_mut().unwrap();
+        v3_fields.remove("networks");
+        v3_fields.insert("version".to_string(), 3.into());
+        v3_fields.insert("wifi_ssid".to_string(), "home".into());
+        v3_fields.insert("wifi_pass".to_string(), "secret".into());
+
+        assert_eq!(load(v3).unwrap(), expected());
+    }
+
+    #[test]
+    fn v3_without_wifi_has_no_networks() {
+        let mut v3 = serde_json::to_value(expected()).unwrap();
+        let v3_fields = v3.as_object_mut().unwrap();
+        v3_fields.remove("networks");
+        v3_fields.insert("version".to_string(), 3.into());
+        v3_fields.insert("wifi_ssid".to_string(), "".into());
+        v3_fields.insert("wifi_pass".to_string(), "".into());
+
+        assert_eq!(load(v3).unwrap().networks, []);
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let mut config = expected();
+        config.timezone = "<-03>3".to_string();
+        config.polling.fast_secs = 5;
+        config.schedule.clear();
+
+        assert_eq!(
+            Config::from_json(&config.to_json().unwrap()).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn fields_added_later_take_their_defaults() {
+        let mut stored = serde_json::to_value(expected()).unwrap();
+        for field in [
+            "max_response_bytes",
+            "timezone",
+            "refresh",
+            "battery",
+            "button",
+            "polling",
+            "schedule",
+        ] {
+            stored.as_object_mut().unwrap().remove(field);
+        }
+
+        assert_eq!(load(stored).unwrap(), expected());
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut stored = serde_json::to_value(expected()).unwrap();
+        stored["version"] = (CONFIG_VERSION + 1).into();
+
+        let error = load(stored).unwrap_err();
+        assert!(
+            error.to_string().contains("is newer than supported"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut stored = serde_json::to_value(expected()).unwrap();
+        stored.as_object_mut().unwrap().remove("version");
+        assert!(load(stored).is_err());
+
+        assert!(load(json!({ "version": 0 })).is_err());
+        assert!(Config::from_json(b"not json").is_err());
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::nvs::ConfigStore;
+
+#[cfg(target_os = "espidf")]
+mod nvs {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use embedded_svc::storage::RawStorage;
+    use esp_idf_svc::nvs::EspDefaultNvs;
+    use esp_idf_svc::nvs_storage::EspNvsStorage;
+    use log::*;
+
+    use super::Config;
+
+    const NAMESPACE: &str = "busmonitor";
+    const CONFIG_KEY: &str = "config";
+
+    /// Keeps the configuration as a JSON blob in the NVS partition
+    pub struct ConfigStore {
+        storage: EspNvsStorage,
+    }
+
+    impl ConfigStore {
+        pub fn new(nvs: Arc<EspDefaultNvs>) -> Result<Self> {
+            Ok(ConfigStore {
+                storage: EspNvsStorage::new_default(nvs, NAMESPACE, true)?,
+            })
+        }
+
+        /// Loads the stored configuration, falling back to the compile time
+        /// defaults on first boot (storing them) or if it can't be read.
+        pub fn load(&mut self) -> Result<Config> {
+            let len = match self.storage.len(CONFIG_KEY)? {
+                Some(len) => len,
+                None => {
+                    info!("No stored configuration, saving defaults");
+                    let config = Config::default();
+                    self.save(&config)?;
+                    return Ok(config);
+                }
+            };
+
+            let mut buf = vec![0_u8; len];
+            let data = match self.storage.get_raw(CONFIG_KEY, &mut buf)? {
+                Some(data) => data,
+                None => return Ok(Config::default()),
+            };
+
+            match Config::from_json(data) {
+                Ok(config) => {
+                    // Persist migrations so they only run once
+                    if config.to_json()? != data {
+                        self.save(&config)?;
+                    }
+                    Ok(config)
+                }
+                Err(e) => {
+                    error!("Stored configuration is invalid, using defaults: {}", e);
+                    Ok(Config::default())
+                }
+            }
+        }
+
+        pub fn save(&mut self, config: &Config) -> Result<()> {
+            self.storage.put_raw(CONFIG_KEY, &config.to_json()?)?;
+            Ok(())
+        }
+    }
+}
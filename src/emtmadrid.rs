@@ -1,10 +1,33 @@
-use embedded_svc::http::client::*;
-use embedded_svc::io;
-use esp_idf_svc::http::client::*;
-use serde_json::Value;
+mod response;
+pub mod transport;
 
-pub struct EMTMadridClient<'a> {
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::*;
+use time::OffsetDateTime;
+
+use self::transport::{HttpTransport, Method};
+
+pub const EMT_BASE_URL: &str = "https://openapi.emtmadrid.es";
+
+// Refresh the token this long before the EMT server would expire it
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Arrival times above this mean the EMT has no estimate for the bus
+pub const NO_ESTIMATE: u64 = 19999;
+
+// A bus is still shown as arriving this long after its estimate ran out
+const ARRIVING_GRACE_SECS: u64 = 60;
+
+// EMT API codes returned when the access token is not valid anymore
+const AUTH_ERROR_CODES: [&str; 2] = ["80", "81"];
+
+pub struct EMTMadridClient<'a, T: HttpTransport> {
+    transport: T,
+    base_url: String,
     access_token: Option<String>,
+    token_expiry: Option<Instant>,
     email: &'a str,
     password: &'a str,
 }
@@ -16,115 +39,408 @@ pub struct ArrivalTime {
     pub destination: String,
 }
 
-impl EMTMadridClient<'_> {
-    pub fn new_from_email<'a>(
-        email: &'a str,
-        password: &'a str,
-    ) -> anyhow::Result<EMTMadridClient<'a>> {
-        let mut client = EMTMadridClient {
+/// Arrival times fetched together, they keep counting down from `fetched`
+/// until the next fetch corrects them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arrivals {
+    pub times: Vec<ArrivalTime>,
+    pub fetched: OffsetDateTime,
+}
+
+impl Arrivals {
+    pub fn new(times: Vec<ArrivalTime>, fetched: OffsetDateTime) -> Self {
+        Arrivals { times, fetched }
+    }
+
+    /// The arrival times as of `now`, without the buses long gone
+    pub fn at(&self, now: OffsetDateTime) -> Vec<ArrivalTime> {
+        let elapsed = (now - self.fetched).whole_seconds().max(0) as u64;
+
+        self.times
+            .iter()
+            .filter(|a| a.time > NO_ESTIMATE || a.time + ARRIVING_GRACE_SECS >= elapsed)
+            .map(|a| ArrivalTime {
+                time: if a.time > NO_ESTIMATE {
+                    a.time
+                } else {
+                    a.time.saturating_sub(elapsed)
+                },
+                ..a.clone()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum EmtError {
+    /// The API answered with an error code
+    Api { code: String, description: String },
+    /// The server answered with an unexpected HTTP status
+    Http(u16),
+    /// The request could not be sent or the response could not be read
+    Transport(anyhow::Error),
+    /// The response body is not the JSON we expect
+    Json(serde_json::Error),
+    /// The response is well formed but lacks the requested data
+    MissingData(&'static str),
+}
+
+impl EmtError {
+    /// True when the access token was rejected and logging in again may help
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            EmtError::Api { code, .. } => AUTH_ERROR_CODES.contains(&code.as_str()),
+            EmtError::Http(status) => *status == 401 || *status == 403,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for EmtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmtError::Api { code, description } => {
+                write!(f, "EMT API error {}: {}", code, description)
+            }
+            EmtError::Http(status) => write!(f, "Unexpected HTTP status {}", status),
+            EmtError::Transport(e) => write!(f, "HTTP transport error: {}", e),
+            EmtError::Json(e) => write!(f, "Invalid JSON response: {}", e),
+            EmtError::MissingData(what) => write!(f, "Response without {}", what),
+        }
+    }
+}
+
+impl std::error::Error for EmtError {}
+
+impl From<serde_json::Error> for EmtError {
+    fn from(e: serde_json::Error) -> Self {
+        EmtError::Json(e)
+    }
+}
+
+impl<'a, T: HttpTransport> EMTMadridClient<'a, T> {
+    /// Creates a client talking to `base_url` without logging in, the first
+    /// request will take care of it.
+    pub fn new(transport: T, base_url: &str, email: &'a str, password: &'a str) -> Self {
+        EMTMadridClient {
+            transport,
+            base_url: base_url.trim_end_matches('/').to_string(),
             access_token: None,
+            token_expiry: None,
             email,
             password,
-        };
+        }
+    }
+
+    pub fn new_from_email(
+        transport: T,
+        email: &'a str,
+        password: &'a str,
+    ) -> Result<EMTMadridClient<'a, T>, EmtError> {
+        let mut client = EMTMadridClient::new(transport, EMT_BASE_URL, email, password);
 
         client.login()?;
 
         Ok(client)
     }
 
-    pub fn new_with_token<'a>(
-        token: &'a str,
+    pub fn new_with_token(
+        transport: T,
+        token: &str,
         email: &'a str,
         password: &'a str,
-    ) -> anyhow::Result<EMTMadridClient<'a>> {
-        let client = EMTMadridClient {
-            access_token: Some(String::from(token)),
-            email,
-            password,
-        };
+    ) -> Result<EMTMadridClient<'a, T>, EmtError> {
+        // The expiry of an externally provided token is unknown, it will be
+        // refreshed the first time the API rejects it.
+        let mut client = EMTMadridClient::new(transport, EMT_BASE_URL, email, password);
+        client.access_token = Some(String::from(token));
 
         Ok(client)
     }
 
-    pub fn login(&mut self) -> anyhow::Result<()> {
-        let url = String::from("https://openapi.emtmadrid.es/v1/mobilitylabs/user/login/");
+    fn send(
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> Result<Vec<u8>, EmtError> {
+        let response = self
+            .transport
+            .request(method, url, headers, body)
+            .map_err(EmtError::Transport)?;
 
-        let mut client = EspHttpClient::new(&EspHttpClientConfiguration {
-            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        // API error codes come in a JSON body even with a 4xx/5xx status, only
+        // bail out early on auth failures or when there is nothing to parse
+        if response.status == 401
+            || response.status == 403
+            || (response.status >= 400 && response.body.is_empty())
+        {
+            return Err(EmtError::Http(response.status));
+        }
 
-            ..Default::default()
-        })?;
+        Ok(response.body)
+    }
 
-        let mut request = client.get(&url)?;
+    pub fn login(&mut self) -> Result<(), EmtError> {
+        let url = format!("{}/v1/mobilitylabs/user/login/", self.base_url);
+        let (email, password) = (self.email, self.password);
 
-        request.set_header("email", self.email);
-        request.set_header("password", self.password);
+        let body = self.send(
+            Method::Get,
+            &url,
+            &[("email", email), ("password", password)],
+            None,
+        )?;
 
-        let mut response = request.submit()?;
+        let login = response::parse_login(&body)?;
 
-        let mut body = [0_u8; 3048];
+        self.access_token = Some(login.access_token);
+        self.token_expiry = login.token_sec_expiration.map(|secs| {
+            Instant::now() + Duration::from_secs(secs).saturating_sub(TOKEN_EXPIRY_MARGIN)
+        });
+        Ok(())
+    }
 
-        let (body, _) = io::read_max(response.reader(), &mut body)?;
+    fn token_is_valid(&self) -> bool {
+        match (&self.access_token, self.token_expiry) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(_), Some(expiry)) => Instant::now() < expiry,
+        }
+    }
 
-        let v: Value = serde_json::from_slice(body)?;
+    pub fn get_arrival_times(&mut self, stop_id: &str) -> Result<Vec<ArrivalTime>, EmtError> {
+        if !self.token_is_valid() {
+            info!("EMT access token expired, logging in again");
+            self.login()?;
+        }
 
-        if let Value::String(token) = &v["data"][0]["accessToken"] {
-            self.access_token = Some(token.clone());
-            return Ok(());
-        } else {
-            dbg!(&v);
+        match self.request_arrival_times(stop_id) {
+            Err(e) if e.is_auth_error() => {
+                info!("EMT access token rejected ({}), logging in again", e);
+                self.login()?;
+                self.request_arrival_times(stop_id)
+            }
+            result => result,
         }
-        Err(anyhow::anyhow!("Error logging in, access token not found"))
     }
 
-    pub fn get_arrival_times(&self, stop_id: &str) -> anyhow::Result<Vec<ArrivalTime>> {
+    fn request_arrival_times(&mut self, stop_id: &str) -> Result<Vec<ArrivalTime>, EmtError> {
         let url = format!(
-            "https://openapi.emtmadrid.es/v1/transport/busemtmad/stops/{}/arrives/",
-            stop_id
+            "{}/v1/transport/busemtmad/stops/{}/arrives/",
+            self.base_url, stop_id
         );
+        let token = self.access_token.clone().unwrap_or_default();
 
-        let mut client = EspHttpClient::new(&EspHttpClientConfiguration {
-            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        let body = self.send(
+            Method::Post,
+            &url,
+            &[
+                ("accessToken", token.as_str()),
+                ("Content-Type", "application/json"),
+            ],
+            Some(r#"{"Text_EstimationsRequired_YN" : "Y"}"#),
+        )?;
 
-            ..Default::default()
-        })?;
+        response::parse_arrivals(&body, stop_id)
+    }
+}
 
-        let mut request = client.post(&url)?;
+#[cfg(test)]
+mod tests {
+    use super::transport::{FakeTransport, RecordedRequest};
+    use super::*;
 
-        request.set_header("accessToken", self.access_token.as_ref().unwrap());
-        request.set_header("Content-Type", "application/json");
+    const BASE_URL: &str = "http://emt.test";
 
-        let mut response = request
-            .send_str(r#"{"Text_EstimationsRequired_YN" : "Y"}"#)?
-            .submit()?;
+    const LOGIN_OK: &str = r#"{"code": "01", "description": "Token extended",
+        "data": [{"accessToken": "token-1", "tokenSecExpiration": 86399}]}"#;
 
-        let mut body = [0_u8; 4048];
+    const ARRIVALS_OK: &str = r#"{"code": "00", "description": "Data recovered OK",
+        "data": [{"Arrive": [
+            {"line": "31", "stop": "874", "destination": "Plaza Mayor", "estimateArrive": 248},
+            {"line": "39", "stop": "874", "destination": "Opera", "estimateArrive": 999999}
+        ]}]}"#;
 
-        let (body, _) = io::read_max(response.reader(), &mut body)?;
+    fn client(transport: FakeTransport) -> EMTMadridClient<'static, FakeTransport> {
+        EMTMadridClient::new(transport, BASE_URL, "user@example.com", "secret")
+    }
 
-        let v: Value = serde_json::from_slice(body)?;
+    fn header<'a>(request: &'a RecordedRequest, name: &str) -> Option<&'a str> {
+        request
+            .headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
 
-        let mut arrival_times = Vec::new();
+    #[test]
+    fn login_stores_the_token() {
+        let mut client = client(FakeTransport::new().respond(200, LOGIN_OK));
 
-        if let Value::Array(arrivals) = &v["data"][0]["Arrive"] {
-            for arrival in arrivals {
-                let destination = &arrival["destination"].as_str().unwrap();
-                let line = &arrival["line"].as_str().unwrap();
-                let estimate_arrival_secs = &arrival["estimateArrive"].as_u64().unwrap();
+        client.login().unwrap();
 
-                arrival_times.push(ArrivalTime {
-                    stop: stop_id.to_string(),
-                    line: line.to_string(),
-                    destination: destination.to_string(),
-                    time: *estimate_arrival_secs,
-                });
+        assert_eq!(client.access_token.as_deref(), Some("token-1"));
+        assert!(client.token_is_valid());
+        let request = &client.transport.requests[0];
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.url, "http://emt.test/v1/mobilitylabs/user/login/");
+        assert_eq!(header(request, "email"), Some("user@example.com"));
+        assert_eq!(header(request, "password"), Some("secret"));
+    }
+
+    #[test]
+    fn arrivals_are_parsed() {
+        let mut client = client(
+            FakeTransport::new()
+                .respond(200, LOGIN_OK)
+                .respond(200, ARRIVALS_OK),
+        );
+
+        let arrivals = client.get_arrival_times("874").unwrap();
+
+        assert_eq!(
+            arrivals,
+            vec![
+                ArrivalTime {
+                    time: 248,
+                    stop: "874".to_string(),
+                    line: "31".to_string(),
+                    destination: "Plaza Mayor".to_string(),
+                },
+                ArrivalTime {
+                    time: 999999,
+                    stop: "874".to_string(),
+                    line: "39".to_string(),
+                    destination: "Opera".to_string(),
+                },
+            ]
+        );
+        let request = &client.transport.requests[1];
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(
+            request.url,
+            "http://emt.test/v1/transport/busemtmad/stops/874/arrives/"
+        );
+        assert_eq!(header(request, "accessToken"), Some("token-1"));
+    }
+
+    #[test]
+    fn api_error_codes_are_reported() {
+        let mut client = client(FakeTransport::new().respond(
+            200,
+            r#"{"code": "90", "description": "Invalid stop", "data": []}"#,
+        ));
+        client.access_token = Some("token-1".to_string());
+
+        match client.get_arrival_times("0") {
+            Err(EmtError::Api { code, description }) => {
+                assert_eq!(code, "90");
+                assert_eq!(description, "Invalid stop");
             }
-        } else {
-            return Err(anyhow::anyhow!(
-                "Error getting arrivals, arrivals not found"
-            ));
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejected_login_is_an_http_error() {
+        let mut client = client(FakeTransport::new().respond(401, ""));
+
+        match client.login() {
+            Err(EmtError::Http(401)) => {}
+            other => panic!("Unexpected result {:?}", other),
         }
+        assert_eq!(client.access_token, None);
+    }
+
+    #[test]
+    fn failed_login_is_tried_again_on_the_next_fetch() {
+        let mut client = client(
+            FakeTransport::new()
+                .respond(500, "")
+                .respond(200, LOGIN_OK)
+                .respond(200, ARRIVALS_OK),
+        );
+
+        assert!(client.login().is_err());
+        assert_eq!(client.get_arrival_times("874").unwrap().len(), 2);
 
-        Ok(arrival_times)
+        let requests = &client.transport.requests;
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].url.ends_with("/user/login/"));
+        assert_eq!(header(&requests[2], "accessToken"), Some("token-1"));
+    }
+
+    #[test]
+    fn expired_token_logs_in_again() {
+        let mut client = client(
+            FakeTransport::new()
+                .respond(200, LOGIN_OK)
+                .respond(200, ARRIVALS_OK),
+        );
+        client.access_token = Some("old-token".to_string());
+        client.token_expiry = Some(Instant::now());
+
+        assert_eq!(client.get_arrival_times("874").unwrap().len(), 2);
+
+        let requests = &client.transport.requests;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].url.ends_with("/user/login/"));
+        assert_eq!(header(&requests[1], "accessToken"), Some("token-1"));
+    }
+
+    #[test]
+    fn rejected_token_logs_in_again_once() {
+        let rejections = [
+            (
+                200,
+                r#"{"code": "80", "description": "Token expired", "data": []}"#,
+            ),
+            (
+                200,
+                r#"{"code": "81", "description": "Invalid token", "data": []}"#,
+            ),
+            (401, ""),
+        ];
+
+        for (status, body) in rejections.iter() {
+            let mut client = client(
+                FakeTransport::new()
+                    .respond(*status, body)
+                    .respond(200, LOGIN_OK)
+                    .respond(200, ARRIVALS_OK),
+            );
+            client.access_token = Some("old-token".to_string());
+
+            assert_eq!(client.get_arrival_times("874").unwrap().len(), 2);
+
+            let requests = &client.transport.requests;
+            assert_eq!(requests.len(), 3);
+            assert_eq!(header(&requests[0], "accessToken"), Some("old-token"));
+            assert!(requests[1].url.ends_with("/user/login/"));
+            assert_eq!(header(&requests[2], "accessToken"), Some("token-1"));
+        }
+    }
+
+    #[test]
+    fn second_rejection_is_an_error() {
+        let mut client = client(
+            FakeTransport::new()
+                .respond(401, "")
+                .respond(200, LOGIN_OK)
+                .respond(
+                    200,
+                    r#"{"code": "80", "description": "Token expired", "data": []}"#,
+                ),
+        );
+        client.access_token = Some("old-token".to_string());
+
+        match client.get_arrival_times("874") {
+            Err(e) => assert!(e.is_auth_error(), "Unexpected error {}", e),
+            Ok(arrivals) => panic!("Unexpected arrivals {:?}", arrivals),
+        }
+        // No more logins nor retries after the second rejection
+        assert_eq!(client.transport.requests.len(), 3);
     }
 }
@@ -0,0 +1,218 @@
+use log::*;
+use serde::de::Deserializer;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{ArrivalTime, EmtError};
+
+// Codes the EMT API uses for successful responses
+const OK_CODES: [&str; 2] = ["00", "01"];
+
+/// Every EMT API response, `data` has a different shape (or is missing) on
+/// errors so it is only converted once `code` is known to be fine
+#[derive(Deserialize)]
+struct Envelope {
+    code: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Login {
+    pub access_token: String,
+    #[serde(default)]
+    pub token_sec_expiration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct StopArrivals {
+    /// Converted one by one, so a malformed entry is skipped alone
+    #[serde(rename = "Arrive", default)]
+    arrive: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Arrive {
+    #[serde(default, deserialize_with = "lenient_string")]
+    line: Option<String>,
+    #[serde(default, deserialize_with = "lenient_string")]
+    destination: Option<String>,
+    #[serde(default, deserialize_with = "lenient_u64")]
+    estimate_arrive: Option<u64>,
+}
+
+// Field helpers that turn a value of an unexpected type into None, so a single
+// odd entry is skipped instead of failing the whole response.
+fn lenient_string<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(d)? {
+        Value::String(s) => Some(s),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    })
+}
+
+fn lenient_u64<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    Ok(match Value::deserialize(d)? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// Decodes a response body, returning its `data` when the response code is a
+/// successful one
+fn decode(body: &[u8]) -> Result<Value, EmtError> {
+    let envelope: Envelope = serde_json::from_slice(body)?;
+    if OK_CODES.contains(&envelope.code.as_str()) {
+        Ok(envelope.data)
+    } else {
+        Err(EmtError::Api {
+            code: envelope.code,
+            description: envelope.description,
+        })
+    }
+}
+
+pub fn parse_login(body: &[u8]) -> Result<Login, EmtError> {
+    let logins: Vec<Login> = serde_json::from_value(decode(body)?)?;
+    logins
+        .into_iter()
+        .next()
+        .ok_or(EmtError::MissingData("access token"))
+}
+
+/// Extracts the arrivals in `data[0].Arrive`. The body is decoded once, then
+/// each entry is converted on its own and skipped when it is malformed.
+pub fn parse_arrivals(body: &[u8], stop_id: &str) -> Result<Vec<ArrivalTime>, EmtError> {
+    let stops: Vec<StopArrivals> = serde_json::from_value(decode(body)?)?;
+    let stop = stops
+        .into_iter()
+        .next()
+        .ok_or(EmtError::MissingData("arrivals"))?;
+
+    let mut arrival_times = Vec::new();
+
+    for entry in stop.arrive {
+        match serde_json::from_value(entry) {
+            Ok(Arrive {
+                line: Some(line),
+                destination,
+                estimate_arrive: Some(time),
+            }) => arrival_times.push(ArrivalTime {
+                stop: stop_id.to_string(),
+                line,
+                destination: destination.unwrap_or_default(),
+                time,
+            }),
+            Ok(Arrive { line, .. }) => {
+                warn!(
+                    "Skipping malformed arrival for line {:?} at stop {}",
+                    line, stop_id
+                );
+            }
+            Err(e) => warn!("Skipping malformed arrival at stop {}: {}", stop_id, e),
+        }
+    }
+
+    Ok(arrival_times)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIN: &[u8] = include_bytes!("../../tests/fixtures/login.json");
+    const ARRIVALS: &[u8] = include_bytes!("../../tests/fixtures/arrivals.json");
+    const ARRIVALS_MALFORMED: &[u8] =
+        include_bytes!("../../tests/fixtures/arrivals_malformed.json");
+    const ERROR_TOKEN: &[u8] = include_bytes!("../../tests/fixtures/error_token.json");
+    const ERROR_STOP: &[u8] = include_bytes!("../../tests/fixtures/error_stop.json");
+
+    fn times(arrivals: &[ArrivalTime]) -> Vec<(&str, &str, u64)> {
+        arrivals
+            .iter()
+            .map(|a| (a.line.as_str(), a.destination.as_str(), a.time))
+            .collect()
+    }
+
+    #[test]
+    fn login() {
+        let login = parse_login(LOGIN).unwrap();
+
+        assert_eq!(login.access_token, "3bd5855a-ed3d-41d5-8b4b-182726f86031");
+        assert_eq!(login.token_sec_expiration, Some(86399));
+    }
+
+    #[test]
+    fn arrivals() {
+        let arrivals = parse_arrivals(ARRIVALS, "874").unwrap();
+
+        assert_eq!(
+            times(&arrivals),
+            vec![
+                ("31", "PLAZA MAYOR", 248),
+                ("65", "PLAZA JACINTO BENAVENTE", 532),
+                ("138", "CRISTO REY", 999999),
+            ]
+        );
+        assert!(arrivals.iter().all(|a| a.stop == "874"));
+    }
+
+    #[test]
+    fn malformed_arrivals_are_skipped() {
+        let arrivals = parse_arrivals(ARRIVALS_MALFORMED, "874").unwrap();
+
+        // Not an object, no line and no estimate are dropped, numbers given as
+        // strings (or the other way around) are taken
+        assert_eq!(
+            times(&arrivals),
+            vec![("31", "PLAZA MAYOR", 37), ("39", "", 410)]
+        );
+    }
+
+    #[test]
+    fn api_error_codes() {
+        for (body, expected) in [(ERROR_TOKEN, "80"), (ERROR_STOP, "90")].iter() {
+            match parse_arrivals(body, "874") {
+                Err(EmtError::Api { code, .. }) => assert_eq!(code, *expected),
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+
+        match parse_login(ERROR_TOKEN) {
+            Err(e) => assert!(e.is_auth_error()),
+            Ok(_) => panic!("Login without a token"),
+        }
+    }
+
+    #[test]
+    fn invalid_json() {
+        let truncated = &ARRIVALS[..ARRIVALS.len() / 2];
+
+        for body in [truncated, b"", b"<html>Bad gateway</html>"].iter() {
+            match parse_arrivals(body, "874") {
+                Err(EmtError::Json(_)) => {}
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_data() {
+        let body = br#"{"code": "00", "description": "OK", "data": []}"#;
+
+        match parse_arrivals(body, "874") {
+            Err(EmtError::MissingData(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match parse_login(body) {
+            Err(EmtError::MissingData(_)) => {}
+            Ok(_) => panic!("Login without a token"),
+            Err(e) => panic!("Unexpected error {}", e),
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use std::fmt;
+
+use anyhow::bail;
+
+/// Largest response body accepted unless the configuration sets another one,
+/// busy stops with text estimations easily go over a few KB
+pub const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024;
+
+const READ_CHUNK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Post => write!(f, "POST"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Minimal HTTP interface needed by the EMT client, so the API logic does not
+/// depend on the ESP-IDF HTTP stack and can run on the host.
+pub trait HttpTransport {
+    fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> anyhow::Result<Response>;
+}
+
+/// Reads a whole response body in small chunks, growing the buffer as needed
+/// instead of reserving the worst case up front, and failing once `max_size`
+/// is exceeded rather than truncating silently.
+fn read_body<E>(
+    mut read: impl FnMut(&mut [u8]) -> Result<usize, E>,
+    size_hint: Option<usize>,
+    max_size: usize,
+) -> anyhow::Result<Vec<u8>>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(size) = size_hint {
+        if size > max_size {
+            bail!("Response body of {} bytes exceeds {} bytes", size, max_size);
+        }
+    }
+
+    let mut body = Vec::with_capacity(size_hint.unwrap_or(READ_CHUNK_SIZE));
+    let mut chunk = [0_u8; READ_CHUNK_SIZE];
+
+    loop {
+        let len = read(&mut chunk)?;
+        if len == 0 {
+            return Ok(body);
+        }
+        if body.len() + len > max_size {
+            bail!("Response body exceeds {} bytes", max_size);
+        }
+        body.extend_from_slice(&chunk[..len]);
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::esp::EspTransport;
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use embedded_svc::http::client::*;
+    use embedded_svc::http::{Headers, Status};
+    use embedded_svc::io::Read;
+    use esp_idf_svc::http::client::*;
+
+    use super::{read_body, HttpTransport, Method, Response, DEFAULT_MAX_BODY_SIZE};
+
+    /// HTTPS transport backed by the ESP-IDF http client and certificate bundle
+    pub struct EspTransport {
+        max_body_size: usize,
+    }
+
+    impl EspTransport {
+        pub fn new() -> Self {
+            EspTransport {
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+            }
+        }
+
+        pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+            self.max_body_size = max_body_size;
+            self
+        }
+    }
+
+    impl Default for EspTransport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl HttpTransport for EspTransport {
+        fn request(
+            &mut self,
+            method: Method,
+            url: &str,
+            headers: &[(&str, &str)],
+            body: Option<&str>,
+        ) -> anyhow::Result<Response> {
+            let mut client = EspHttpClient::new(&EspHttpClientConfiguration {
+                crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+
+                ..Default::default()
+            })?;
+
+            let mut request = match method {
+                Method::Get => client.get(url)?,
+                Method::Post => client.post(url)?,
+            };
+
+            for (name, value) in headers {
+                request.set_header(name, value);
+            }
+
+            let mut response = match body {
+                Some(body) => request.send_str(body)?.submit()?,
+                None => request.submit()?,
+            };
+
+            let status = response.status();
+            let size_hint = response.content_len();
+
+            let mut reader = response.reader();
+            let body = read_body(|buf| reader.read(buf), size_hint, self.max_body_size)?;
+
+            Ok(Response { status, body })
+        }
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub use self::tcp::TcpTransport;
+
+#[cfg(not(target_os = "espidf"))]
+mod tcp {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use anyhow::{anyhow, bail};
+
+    use super::{read_body, HttpTransport, Method, Response, DEFAULT_MAX_BODY_SIZE};
+
+    // Room for the status line and headers on top of the body limit
+    const MAX_HEADER_SIZE: usize = 4096;
+
+    /// Plain HTTP/1.0 transport over std TCP sockets for host builds.
+    ///
+    /// It does not speak TLS, so it is meant for local mock servers or an
+    /// HTTP proxy in front of the EMT API.
+    pub struct TcpTransport {
+        timeout: Duration,
+        max_body_size: usize,
+    }
+
+    impl TcpTransport {
+        pub fn new() -> Self {
+            TcpTransport {
+                timeout: Duration::from_secs(20),
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+            }
+        }
+
+        pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+            self.max_body_size = max_body_size;
+            self
+        }
+    }
+
+    impl Default for TcpTransport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    fn split_url(url: &str) -> anyhow::Result<(&str, &str)> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Only http:// urls are supported, got {}", url))?;
+
+        Ok(match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        })
+    }
+
+    fn parse_response(raw: &[u8]) -> anyhow::Result<Response> {
+        let header_end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("Malformed HTTP response, headers not terminated"))?;
+
+        let head = std::str::from_utf8(&raw[..header_end])?;
+        let status_line = head.lines().next().unwrap_or("");
+        let status = match status_line.split_whitespace().nth(1) {
+            Some(code) => code.parse::<u16>()?,
+            None => bail!("Malformed HTTP status line: {}", status_line),
+        };
+
+        Ok(Response {
+            status,
+            body: raw[header_end + 4..].to_vec(),
+        })
+    }
+
+    impl HttpTransport for TcpTransport {
+        fn request(
+            &mut self,
+            method: Method,
+            url: &str,
+            headers: &[(&str, &str)],
+            body: Option<&str>,
+        ) -> anyhow::Result<Response> {
+            let (host, path) = split_url(url)?;
+            let addr = if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:80", host)
+            };
+
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+
+            // HTTP/1.0 keeps the server from answering with chunked encoding
+            let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+            for (name, value) in headers {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            let body = body.unwrap_or("");
+            request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+
+            stream.write_all(request.as_bytes())?;
+
+            let raw = read_body(
+                |buf| stream.read(buf),
+                None,
+                self.max_body_size + MAX_HEADER_SIZE,
+            )?;
+
+            parse_response(&raw)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn urls_are_split_into_host_and_path() {
+            assert_eq!(
+                split_url("http://localhost:8080/v1/user/login/").unwrap(),
+                ("localhost:8080", "/v1/user/login/")
+            );
+            assert_eq!(split_url("http://emt.test").unwrap(), ("emt.test", "/"));
+            assert!(split_url("https://openapi.emtmadrid.es/").is_err());
+        }
+
+        #[test]
+        fn status_and_body_are_parsed() {
+            let response = parse_response(
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"code\": \"00\"}",
+            )
+            .unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"{\"code\": \"00\"}");
+
+            let response = parse_response(b"HTTP/1.0 401 Unauthorized\r\n\r\n").unwrap();
+            assert_eq!(response.status, 401);
+            assert!(response.body.is_empty());
+        }
+
+        #[test]
+        fn requests_go_over_tcp() {
+            use std::net::TcpListener;
+            use std::thread;
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/v1/stops/874/", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                // Up to the end of the body the client sends
+                let mut request = Vec::new();
+                let mut chunk = [0; 256];
+                while !request.ends_with(b"\r\n\r\n{}") {
+                    let len = stream.read(&mut chunk).unwrap();
+                    assert!(len > 0, "Request cut short");
+                    request.extend_from_slice(&chunk[..len]);
+                }
+                stream
+                    .write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nbody")
+                    .unwrap();
+                String::from_utf8(request).unwrap()
+            });
+
+            let response = TcpTransport::new()
+                .request(
+                    Method::Post,
+                    &url,
+                    &[("accessToken", "token-1")],
+                    Some("{}"),
+                )
+                .unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"body");
+
+            let request = server.join().unwrap();
+            assert!(request.starts_with("POST /v1/stops/874/ HTTP/1.0\r\nHost: 127.0.0.1:"));
+            assert!(request.contains("\r\naccessToken: token-1\r\n"));
+            assert!(request.ends_with("\r\nContent-Length: 2\r\n\r\n{}"));
+        }
+
+        #[test]
+        fn malformed_responses_are_errors() {
+            // Headers never terminated, no status, a status that isn't one
+            assert!(parse_response(b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n").is_err());
+            assert!(parse_response(b"HTTP/1.0\r\n\r\n").is_err());
+            assert!(parse_response(b"HTTP/1.0 OK\r\n\r\n").is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+pub use self::fake::{FakeTransport, RecordedRequest};
+
+#[cfg(test)]
+mod fake {
+    use std::collections::VecDeque;
+
+    use super::{HttpTransport, Method, Response};
+
+    #[derive(Debug, Clone)]
+    pub struct RecordedRequest {
+        pub method: Method,
+        pub url: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Option<String>,
+    }
+
+    /// In-memory transport replaying canned responses in order and recording
+    /// every request it receives.
+    #[derive(Default)]
+    pub struct FakeTransport {
+        pub responses: VecDeque<Response>,
+        pub requests: Vec<RecordedRequest>,
+    }
+
+    impl FakeTransport {
+        pub fn new() -> Self {
+            Default::default()
+        }
+
+        pub fn respond(mut self, status: u16, body: &str) -> Self {
+            self.responses.push_back(Response {
+                status,
+                body: body.as_bytes().to_vec(),
+            });
+            self
+        }
+    }
+
+    impl HttpTransport for FakeTransport {
+        fn request(
+            &mut self,
+            method: Method,
+            url: &str,
+            headers: &[(&str, &str)],
+            body: Option<&str>,
+        ) -> anyhow::Result<Response> {
+            self.requests.push(RecordedRequest {
+                method,
+                url: url.to_string(),
+                headers: headers
+                    .iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect(),
+                body: body.map(String::from),
+            });
+
+            self.responses
+                .pop_front()
+                .ok_or_else(|| anyhow::anyhow!("No canned response left for {} {}", method, url))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use super::*;
+
+    /// In-memory body handed out at most `chunk` bytes per read
+    struct ChunkedBody {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl ChunkedBody {
+        fn new(size: usize, chunk: usize) -> Self {
+            ChunkedBody {
+                data: (0..size).map(|n| n as u8).collect(),
+                chunk,
+            }
+        }
+    }
+
+    impl Read for ChunkedBody {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn read_all(
+        mut body: ChunkedBody,
+        size_hint: Option<usize>,
+        max_size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        read_body(|buf| body.read(buf), size_hint, max_size)
+    }
+
+    #[test]
+    fn whole_body_is_read() {
+        let expected = ChunkedBody::new(2000, 2000).data;
+
+        // Short reads, reads of a whole chunk and a body ending mid chunk
+        for chunk in [1, 7, READ_CHUNK_SIZE, 4096] {
+            let body = read_all(ChunkedBody::new(2000, chunk), None, 2000).unwrap();
+            assert_eq!(body, expected, "Reading {} bytes at a time", chunk);
+        }
+        assert_eq!(
+            read_all(ChunkedBody::new(2000, 100), Some(2000), 2000).unwrap(),
+            expected
+        );
+        assert!(read_all(ChunkedBody::new(0, 100), None, 2000)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected() {
+        let error = read_all(ChunkedBody::new(2001, 100), None, 2000).unwrap_err();
+        assert_eq!(error.to_string(), "Response body exceeds 2000 bytes");
+
+        // A wrong or missing length is no way around the limit
+        assert!(read_all(ChunkedBody::new(2001, 100), Some(10), 2000).is_err());
+    }
+
+    #[test]
+    fn announced_size_over_the_limit_is_rejected_before_reading() {
+        let mut reads = 0;
+        let result = read_body(
+            |_: &mut [u8]| -> io::Result<usize> {
+                reads += 1;
+                Ok(0)
+            },
+            Some(2001),
+            2000,
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Response body of 2001 bytes exceeds 2000 bytes"
+        );
+        assert_eq!(reads, 0);
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let result = read_body(
+            |_: &mut [u8]| -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            },
+            None,
+            2000,
+        );
+        assert_eq!(result.unwrap_err().to_string(), "timed out");
+    }
+}
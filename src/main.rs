@@ -1,36 +1,88 @@
+#[cfg(any(target_os = "espidf", test))]
+pub mod backoff;
+pub mod clock;
+pub mod config;
 pub mod emtmadrid;
 pub mod peripherals;
+pub mod polling;
+pub mod provisioning;
+pub mod schedule;
+#[cfg(target_os = "espidf")]
 pub mod wifi;
 
-use std::ptr;
+#[cfg(target_os = "espidf")]
+use std::collections::BTreeSet;
+#[cfg(target_os = "espidf")]
+use std::net::{TcpListener, UdpSocket};
+#[cfg(target_os = "espidf")]
+use std::sync::{mpsc, Arc};
+#[cfg(target_os = "espidf")]
 use std::thread;
+#[cfg(target_os = "espidf")]
 use std::time::*;
 
 use log::*;
 
+#[cfg(target_os = "espidf")]
+use anyhow::anyhow;
 use anyhow::Result;
-use time::UtcOffset;
 
+use crate::emtmadrid::transport::HttpTransport;
 use crate::emtmadrid::ArrivalTime;
 use crate::emtmadrid::EMTMadridClient;
 
+#[cfg(target_os = "espidf")]
+use crate::clock::{TimeSync, TimeZone};
+#[cfg(target_os = "espidf")]
+use crate::config::{Config, ConfigStore, LongPressAction};
+#[cfg(target_os = "espidf")]
+use crate::emtmadrid::transport::EspTransport;
+#[cfg(target_os = "espidf")]
+use crate::emtmadrid::{Arrivals, EMT_BASE_URL};
+#[cfg(target_os = "espidf")]
+use crate::peripherals::button::{self, Press};
+#[cfg(target_os = "espidf")]
 use crate::peripherals::display::DisplayMessage;
+#[cfg(target_os = "espidf")]
+use crate::provisioning::{dns, AP_SSID, PORTAL_IP};
+#[cfg(target_os = "espidf")]
+use crate::schedule::active_time_left;
 
-use esp_idf_svc::sntp;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::EspDefaultNvs;
+#[cfg(target_os = "espidf")]
 use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
-use esp_idf_sys::time_t;
-
+#[cfg(target_os = "espidf")]
 use time::OffsetDateTime;
 
-use esp_idf_svc::sntp::SyncStatus;
+/// Longest wait for the first SNTP reply on boot
+#[cfg(target_os = "espidf")]
+const SNTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Shorter waits for the next active window are not worth a deep sleep
+#[cfg(target_os = "espidf")]
+const MIN_SLEEP: time::Duration = time::Duration::minutes(2);
+
+/// The setup portal reboots to retry the saved Wi-Fi networks after being
+/// left unused this long
+#[cfg(target_os = "espidf")]
+const PORTAL_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-const EMT_USER: &str = env!("EMT_USER");
-const EMT_PASS: &str = env!("EMT_PASS");
+/// Marks a restart into the setup portal. The uninitialized RTC memory keeps
+/// it over the reset, and holds garbage after a power on.
+#[cfg(target_os = "espidf")]
+const SETUP_MAGIC: u32 = 0x5e70_0b75;
 
+#[cfg(target_os = "espidf")]
+#[link_section = ".rtc_noinit"]
+static mut SETUP_REQUEST: u32 = 0;
+
+#[cfg(target_os = "espidf")]
 extern "C" {
     fn esp_deep_sleep_start() -> i32;
 }
 
+#[cfg(target_os = "espidf")]
 fn main() -> Result<()> {
     // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
     // or else some patches to the runtime implemented by esp-idf-sys might not link properly.
@@ -39,81 +91,401 @@ fn main() -> Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    let display = peripherals::init()?;
+    let default_nvs = Arc::new(EspDefaultNvs::new()?);
+    let config = ConfigStore::new(default_nvs.clone())?.load()?;
+
+    let (display, presses) = peripherals::init(config.clone())?;
 
-    let mut _wifi = wifi::setup_wifi()?;
+    if take_setup_request() {
+        info!("Setup requested with the button");
+        return provision(default_nvs, &config, &display, None);
+    }
+
+    let wifi = if config.networks.is_empty() {
+        info!("No Wifi credentials configured");
+        None
+    } else {
+        match wifi::setup_wifi(default_nvs.clone(), &config.networks) {
+            Ok(wifi) => Some(wifi),
+            Err(e) => {
+                error!("Error connecting to Wifi: {}", e);
+                None
+            }
+        }
+    };
+
+    let wifi = match wifi {
+        Some(wifi) => wifi,
+        None => {
+            // Saved networks may just be out of range, they are tried again
+            // once nobody is using the portal
+            let idle_timeout = Some(PORTAL_IDLE_TIMEOUT).filter(|_| !config.networks.is_empty());
+            return provision(default_nvs, &config, &display, idle_timeout);
+        }
+    };
+    let link = wifi::supervise(wifi, display.clone())?;
 
     display.send(DisplayMessage::Message(
         "EMTMadrid connecting...".to_string(),
     ))?;
     display.send(DisplayMessage::Update)?;
 
-    let client = EMTMadridClient::new_from_email(EMT_USER, EMT_PASS)?;
+    let transport = EspTransport::new().with_max_body_size(config.max_response_bytes as usize);
+    let mut client =
+        EMTMadridClient::new(transport, EMT_BASE_URL, &config.emt_user, &config.emt_pass);
+    let stops: Vec<&str> = config.stops.iter().map(|s| s.id.as_str()).collect();
 
-    display.send(DisplayMessage::Message("EMTMadrid Login OK".to_string()))?;
+    // Not fatal, the client logs in again on the next fetch
+    let login = match client.login() {
+        Ok(()) => "EMTMadrid Login OK",
+        Err(e) => {
+            error!("EMTMadrid login failed: {}", e);
+            "EMTMadrid Login failed"
+        }
+    };
+    display.send(DisplayMessage::Message(login.to_string()))?;
     display.send(DisplayMessage::Update)?;
 
-    let sntp = sntp::EspSntp::new_default()?;
     display.send(DisplayMessage::Message(
         "Updating time via SNTP".to_string(),
     ))?;
+    display.send(DisplayMessage::Update)?;
 
-    while sntp.get_sync_status() != SyncStatus::Completed {}
-
-    display.send(DisplayMessage::Message("Time updated".to_string()))?;
+    let time_sync = TimeSync::start(SNTP_TIMEOUT, display.clone())?;
+    display.send(DisplayMessage::Message(
+        if time_sync.is_synced() {
+            "Time updated"
+        } else {
+            "Time not synced"
+        }
+        .to_string(),
+    ))?;
 
-    let actual_time = get_time();
+    let actual_time = config.timezone().now();
 
     display.send(DisplayMessage::Message(actual_time.to_string()))?;
     display.send(DisplayMessage::Update)?;
 
-    for _n in 1..200 {
+    // A press outside the schedule keeps the monitor active for a while
+    let on_demand = Duration::from_secs(config.button.on_demand_minutes as u64 * 60);
+    let mut on_demand_until = if button::woke_up() {
+        info!("Woken up by the button");
+        Some(Instant::now() + on_demand)
+    } else {
+        None
+    };
+
+    let redraw = Duration::from_secs(config.polling.redraw_secs.max(1) as u64);
+
+    // Stops missing from the configuration, already logged
+    let mut unconfigured_logged = BTreeSet::new();
+
+    // A clock that never synced can't tell the time of the week, keep going
+    let schedule = config.schedule();
+    loop {
+        while !time_sync.is_synced()
+            || schedule.is_active(OffsetDateTime::now_utc())
+            || on_demand_until.map_or(false, |until| Instant::now() < until)
+        {
+            if !link.is_up() {
+                // Show the Wi-Fi as offline until it is back
+                info!("Waiting for Wifi to fetch the arrivals");
+                display.send(DisplayMessage::Update)?;
+
+                // Not past the active time, it may be over by then
+                let timeout = active_time_left(
+                    &schedule,
+                    OffsetDateTime::now_utc(),
+                    time_sync.is_synced(),
+                    on_demand_until.map(|until| until.saturating_duration_since(Instant::now())),
+                    Duration::from_secs(config.polling.slow_secs.max(1) as u64),
+                );
+                if !link.wait_up_timeout(timeout) {
+                    info!("No Wifi after {:?}", timeout);
+                    continue;
+                }
+            }
+
+            let arrivals = Arrivals::new(
+                get_my_arrivals(&mut client, &stops),
+                OffsetDateTime::now_utc(),
+            );
+            let interval = polling::interval(&config, &arrivals.times, &mut unconfigured_logged);
+            info!("Next fetch in {:?}", interval);
+
+            // A press fetches the arrivals again right away
+            match show_countdown(&display, &presses, &arrivals, interval, redraw)? {
+                Some(Press::Short) => on_demand_until = Some(Instant::now() + on_demand),
+                Some(Press::Long) => match config.button.long_press {
+                    LongPressAction::Setup => restart_into_setup(),
+                    LongPressAction::FullRefresh => display.send(DisplayMessage::FullRefresh)?,
+                },
+                None => {}
+            }
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let wake = schedule
+            .next_start(now)
+            .ok_or_else(|| anyhow!("No active window to wake up for"))?;
+
+        // The RTC drifts while sleeping, a wake up a bit early just waits
+        let left = wake - now;
+        if left > MIN_SLEEP {
+            return sleep_until(&display, &config.timezone(), now, wake);
+        }
+        thread::sleep(Duration::from_millis(
+            left.whole_milliseconds().max(0) as u64
+        ));
+    }
+}
+
+/// Shows `arrivals` counting down locally, redrawn every `redraw`, until it is
+/// time to fetch them again, or the button is pressed
+#[cfg(target_os = "espidf")]
+fn show_countdown(
+    display: &mpsc::SyncSender<DisplayMessage>,
+    presses: &mpsc::Receiver<Press>,
+    arrivals: &Arrivals,
+    interval: Duration,
+    redraw: Duration,
+) -> Result<Option<Press>> {
+    let next_fetch = Instant::now() + interval;
+    loop {
         display.send(DisplayMessage::Clear)?;
-        let arrivals = get_my_arrivals(&client);
-        display.send(DisplayMessage::Arrivals(arrivals))?;
+        display.send(DisplayMessage::Arrivals(arrivals.clone()))?;
         display.send(DisplayMessage::Update)?;
 
-        thread::sleep(Duration::from_millis(5000));
+        let left = next_fetch.saturating_duration_since(Instant::now());
+        if let Ok(press) = presses.recv_timeout(left.min(redraw)) {
+            return Ok(Some(press));
+        }
+        if Instant::now() >= next_fetch {
+            return Ok(None);
+        }
+    }
+}
+
+/// Shows when the monitor wakes up and deep sleeps until then, the e-ink
+/// panel keeps the message while the power is off.
+#[cfg(target_os = "espidf")]
+fn sleep_until(
+    display: &mpsc::SyncSender<DisplayMessage>,
+    timezone: &TimeZone,
+    now: OffsetDateTime,
+    wake: OffsetDateTime,
+) -> Result<()> {
+    let local = timezone.to_local(wake);
+    let mut until = format!("{:02}:{:02}", local.hour(), local.minute());
+    if local.date() != timezone.to_local(now).date() {
+        until = format!("{} {}", &local.weekday().to_string()[..3], until);
     }
+    info!("Sleeping until {}", local);
 
-    drop(display);
+    display.send(DisplayMessage::Clear)?;
+    display.send(DisplayMessage::Message(format!("Sleeping until {}", until)))?;
+    display.send(DisplayMessage::Message(
+        "Press the button to wake up".to_string(),
+    ))?;
+    // A clean frame, it stays on the panel for hours
+    display.send(DisplayMessage::FullRefresh)?;
+    display.send(DisplayMessage::Sleep)?;
     thread::sleep(Duration::from_millis(5000));
 
+    let micros = (wake - OffsetDateTime::now_utc())
+        .whole_microseconds()
+        .max(0) as u64;
+    button::enable_wakeup()?;
     unsafe {
+        esp_idf_sys::esp_sleep_enable_timer_wakeup(micros);
         esp_deep_sleep_start();
     }
     Ok(())
 }
 
-pub fn get_time() -> time::OffsetDateTime {
-    let timestamp;
+/// Restarts into the setup portal, asked for with a long press of the button
+#[cfg(target_os = "espidf")]
+fn restart_into_setup() -> ! {
     unsafe {
-        let timer: *mut time_t = ptr::null_mut();
-        timestamp = esp_idf_sys::time(timer);
+        SETUP_REQUEST = SETUP_MAGIC;
+        esp_idf_sys::esp_restart();
     }
+    unreachable!()
+}
 
-    let actual_time = OffsetDateTime::from_unix_timestamp(timestamp as i64)
-        .unwrap()
-        .to_offset(UtcOffset::from_hms(1, 0, 0).unwrap());
-    actual_time
+/// Whether the restart was asked to open the setup portal, clearing the request
+#[cfg(target_os = "espidf")]
+fn take_setup_request() -> bool {
+    unsafe {
+        let requested = SETUP_REQUEST == SETUP_MAGIC;
+        SETUP_REQUEST = 0;
+        requested
+    }
 }
 
-fn get_my_arrivals(client: &EMTMadridClient) -> Vec<ArrivalTime> {
-    let mut arrivals = Vec::<ArrivalTime>::new();
-    match client.get_arrival_times("874") {
-        Ok(arr) => {
-            arrivals = arr.clone();
-        }
-        Err(e) => {
-            error!("Error getting arrival times: {}", e);
+/// Serves the setup portal on a SoftAP until a new configuration is saved,
+/// or until it was left unused for `idle_timeout`, then reboots.
+#[cfg(target_os = "espidf")]
+fn provision(
+    default_nvs: Arc<EspDefaultNvs>,
+    config: &Config,
+    display: &mpsc::SyncSender<DisplayMessage>,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let _wifi = wifi::start_access_point(default_nvs.clone())?;
+
+    display.send(DisplayMessage::Clear)?;
+    display.send(DisplayMessage::Message("Setup: join the Wi-Fi".to_string()))?;
+    display.send(DisplayMessage::Message(format!("  {}", AP_SSID)))?;
+    display.send(DisplayMessage::Message(format!(
+        "and open http://{}/",
+        PORTAL_IP
+    )))?;
+    display.send(DisplayMessage::Update)?;
+
+    let dns_socket = UdpSocket::bind("0.0.0.0:53")?;
+    thread::Builder::new().stack_size(4_000).spawn(move || {
+        if let Err(e) = dns::serve(dns_socket, PORTAL_IP) {
+            error!("Captive portal DNS stopped: {}", e);
         }
+    })?;
+
+    let mut store = ConfigStore::new(default_nvs)?;
+    let saved = provisioning::serve(
+        TcpListener::bind("0.0.0.0:80")?,
+        config,
+        idle_timeout,
+        |new_config| store.save(&new_config),
+    )?;
+
+    let message = if saved {
+        "Saved, restarting..."
+    } else {
+        "Retrying the Wi-Fi..."
+    };
+    display.send(DisplayMessage::Message(message.to_string()))?;
+    display.send(DisplayMessage::Update)?;
+    thread::sleep(Duration::from_millis(3000));
+
+    unsafe {
+        esp_idf_sys::esp_restart();
     }
-    match client.get_arrival_times("1455") {
-        Ok(arr) => {
-            arrivals.append(&mut arr.clone());
+    Ok(())
+}
+
+/// Host build: fetches and prints the arrivals of the stops given as arguments
+/// through a plain HTTP endpoint (EMT_BASE_URL), like a local mock of the API.
+#[cfg(all(not(target_os = "espidf"), not(feature = "simulator")))]
+fn main() -> Result<()> {
+    use crate::emtmadrid::transport::TcpTransport;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let base_url = std::env::var("EMT_BASE_URL")?;
+    let user = std::env::var("EMT_USER")?;
+    let pass = std::env::var("EMT_PASS")?;
+
+    let stops: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let mut client = EMTMadridClient::new(TcpTransport::new(), &base_url, &user, &pass);
+
+    for arrival in get_my_arrivals(&mut client, &stops) {
+        println!(
+            "{:>4} {:>3} {:15} {}s",
+            arrival.stop, arrival.line, arrival.destination, arrival.time
+        );
+    }
+    Ok(())
+}
+
+/// Host build with the display simulator: shows the screens of the device in a
+/// window, or writes them as PNG files with `--png DIR`. Arrivals come from
+/// EMT_BASE_URL when set, canned ones are shown otherwise.
+#[cfg(all(not(target_os = "espidf"), feature = "simulator"))]
+fn main() -> Result<()> {
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use time::OffsetDateTime;
+
+    use crate::config::Config;
+    use crate::emtmadrid::transport::TcpTransport;
+    use crate::emtmadrid::Arrivals;
+    use crate::peripherals::display::simulator::{self, Output};
+    use crate::peripherals::display::DisplayMessage;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let output = match args.iter().position(|a| a == "--png") {
+        Some(idx) => Output::Png(PathBuf::from(
+            args.get(idx + 1).map_or("frames", String::as_str),
+        )),
+        None => Output::Window,
+    };
+    // PNG files are written for a single refresh, the window keeps refreshing
+    let refreshes = match output {
+        Output::Png(_) => 1,
+        Output::Window => usize::MAX,
+    };
+
+    let config = Config::default();
+    let (tx, rx) = mpsc::sync_channel::<DisplayMessage>(5);
+
+    let feed_config = config.clone();
+    thread::spawn(move || -> Result<()> {
+        let mut client = match std::env::var("EMT_BASE_URL") {
+            Ok(base_url) => Some(EMTMadridClient::new(
+                TcpTransport::new().with_max_body_size(feed_config.max_response_bytes as usize),
+                &base_url,
+                &feed_config.emt_user,
+                &feed_config.emt_pass,
+            )),
+            Err(_) => None,
+        };
+        let stops: Vec<&str> = feed_config.stops.iter().map(|s| s.id.as_str()).collect();
+
+        tx.send(DisplayMessage::Message("Display ready".to_string()))?;
+        tx.send(DisplayMessage::Update)?;
+
+        for _n in 0..refreshes {
+            let arrivals = match &mut client {
+                Some(client) => get_my_arrivals(client, &stops),
+                None => simulator::sample_arrivals(),
+            };
+            tx.send(DisplayMessage::Battery(simulator::SAMPLE_BATTERY))?;
+            tx.send(DisplayMessage::WiFi(simulator::SAMPLE_WIFI))?;
+            tx.send(DisplayMessage::Clear)?;
+            tx.send(DisplayMessage::Arrivals(Arrivals::new(
+                arrivals,
+                OffsetDateTime::now_utc(),
+            )))?;
+            tx.send(DisplayMessage::Update)?;
+
+            if refreshes > 1 {
+                thread::sleep(Duration::from_millis(5000));
+            }
         }
-        Err(e) => {
-            error!("Error getting arrival times: {}", e);
+        Ok(())
+    });
+
+    simulator::run(config, rx, output)
+}
+
+fn get_my_arrivals<T: HttpTransport>(
+    client: &mut EMTMadridClient<T>,
+    stops: &[&str],
+) -> Vec<ArrivalTime> {
+    let mut arrivals = Vec::<ArrivalTime>::new();
+    for stop in stops {
+        match client.get_arrival_times(stop) {
+            Ok(mut arr) => {
+                arrivals.append(&mut arr);
+            }
+            Err(e) => {
+                error!("Error getting arrival times: {}", e);
+            }
         }
     }
     arrivals.sort();
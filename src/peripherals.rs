@@ -1,16 +1,29 @@
+pub mod battery;
+pub mod button;
 pub mod display;
+#[cfg(target_os = "espidf")]
 use anyhow::Result;
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::prelude::*;
+#[cfg(target_os = "espidf")]
 use std::sync::mpsc;
 
+#[cfg(target_os = "espidf")]
+use self::button::Press;
+#[cfg(target_os = "espidf")]
 use self::display::DisplayMessage;
+#[cfg(target_os = "espidf")]
+use crate::config::Config;
 
-pub fn init() -> Result<mpsc::SyncSender<DisplayMessage>> {
+/// Starts the display, the battery monitor and the button, returns the
+/// channels to draw on the display and to receive the button presses
+#[cfg(target_os = "espidf")]
+pub fn init(config: Config) -> Result<(mpsc::SyncSender<DisplayMessage>, mpsc::Receiver<Press>)> {
     let peripherals = Peripherals::take().unwrap();
     let pins = peripherals.pins;
 
     #[cfg(feature = "ttgo")]
-    let msg_sender = display::start(
+    let backend = display::ttgo::TDisplay::new(
         pins.gpio4,
         pins.gpio16,
         pins.gpio23,
@@ -20,7 +33,8 @@ pub fn init() -> Result<mpsc::SyncSender<DisplayMessage>> {
         pins.gpio5,
     )?;
 
-    let msg_sender = display::start(
+    #[cfg(not(feature = "ttgo"))]
+    let backend = display::eink::Eink::new(
         pins.gpio4,
         pins.gpio16,
         pins.gpio13,
@@ -30,7 +44,16 @@ pub fn init() -> Result<mpsc::SyncSender<DisplayMessage>> {
         pins.gpio5,
     )?;
 
+    let battery = config.battery.clone();
+    let presses = button::spawn(pins.gpio0, &config.button)?;
+    let msg_sender = display::backend::spawn(backend, config)?;
     msg_sender.send(DisplayMessage::Message("Display ready".to_string()))?;
 
-    Ok(msg_sender)
+    #[cfg(esp32)]
+    let battery_pin = pins.gpio34;
+    #[cfg(not(esp32))]
+    let battery_pin = pins.gpio1;
+    battery::spawn(peripherals.adc1, battery_pin, battery, msg_sender.clone())?;
+
+    Ok((msg_sender, presses))
 }
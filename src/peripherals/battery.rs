@@ -0,0 +1,240 @@
+use crate::config::BatteryConfig;
+
+/// Readings taken at once, their median is used to drop the ADC spikes
+pub const SAMPLES: usize = 16;
+
+/// Weight of a new reading in the moving average of the battery voltage
+const SMOOTHING: f32 = 0.2;
+
+/// Battery state shown on the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryStatus {
+    /// Estimated charge, 0 to 100
+    pub percent: f32,
+    /// Powered from USB, the battery is charging (or missing)
+    pub charging: bool,
+}
+
+/// Smooths the battery voltage readings: the median of each burst of samples
+/// drops the spikes, and a moving average the noise left between bursts.
+pub struct VoltageFilter {
+    volts: Option<f32>,
+}
+
+impl VoltageFilter {
+    pub fn new() -> Self {
+        VoltageFilter { volts: None }
+    }
+
+    /// Adds a burst of samples, returns the filtered voltage
+    pub fn push(&mut self, samples: &mut [f32]) -> Option<f32> {
+        let sample = median(samples)?;
+
+        // A jump like plugging in USB is followed right away
+        let volts = match self.volts {
+            Some(volts) if (sample - volts).abs() < 0.2 => volts + (sample - volts) * SMOOTHING,
+            _ => sample,
+        };
+        self.volts = Some(volts);
+        Some(volts)
+    }
+}
+
+impl Default for VoltageFilter {
+    fn default() -> Self {
+        VoltageFilter::new()
+    }
+}
+
+fn median(samples: &mut [f32]) -> Option<f32> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let middle = samples.len() / 2;
+    if samples.len() % 2 == 0 {
+        Some((samples[middle - 1] + samples[middle]) / 2.0)
+    } else {
+        Some(samples[middle])
+    }
+}
+
+/// Charge left at `volts`, interpolated between the points of the discharge
+/// `curve` (volts and percent, by increasing voltage)
+pub fn percent(curve: &[(f32, f32)], volts: f32) -> f32 {
+    let (first, last) = match (curve.first(), curve.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 0.0,
+    };
+
+    if volts <= first.0 {
+        return first.1;
+    }
+    if volts >= last.0 {
+        return last.1;
+    }
+
+    for pair in curve.windows(2) {
+        let ((v0, p0), (v1, p1)) = (pair[0], pair[1]);
+        if volts <= v1 {
+            if v1 <= v0 {
+                return p1;
+            }
+            return p0 + (volts - v0) * (p1 - p0) / (v1 - v0);
+        }
+    }
+    last.1
+}
+
+/// Battery state at the filtered battery voltage `volts`
+pub fn status(config: &BatteryConfig, volts: f32) -> BatteryStatus {
+    BatteryStatus {
+        percent: percent(&config.curve, volts),
+        // The divider sees the USB supply, above any charged cell
+        charging: volts >= config.usb_volts,
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::monitor::spawn;
+
+/// Samples the battery divider on an ADC1 pin and sends its state to the
+/// display: GPIO34 on the ESP32, GPIO1 on the later chips where GPIO34 has no
+/// ADC (or does not exist)
+#[cfg(target_os = "espidf")]
+mod monitor {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use embedded_hal::adc::OneShot;
+    use esp_idf_hal::adc;
+    #[cfg(not(esp32))]
+    use esp_idf_hal::gpio::Gpio1 as BatteryGpio;
+    #[cfg(esp32)]
+    use esp_idf_hal::gpio::Gpio34 as BatteryGpio;
+    use esp_idf_hal::gpio::Unknown;
+    use log::*;
+
+    use super::{status, VoltageFilter, SAMPLES};
+    use crate::config::BatteryConfig;
+    use crate::peripherals::display::DisplayMessage;
+
+    type Pin = BatteryGpio<adc::Atten11dB<adc::ADC1>>;
+
+    pub fn spawn(
+        adc1: adc::ADC1,
+        pin: BatteryGpio<Unknown>,
+        config: BatteryConfig,
+        display: mpsc::SyncSender<DisplayMessage>,
+    ) -> Result<()> {
+        // Calibrated readings are in millivolts
+        let mut adc = adc::PoweredAdc::new(adc1, adc::config::Config::new().calibration(true))?;
+        let mut pin: Pin = pin.into_analog_atten_11db()?;
+
+        thread::Builder::new().stack_size(4096).spawn(move || {
+            let mut filter = VoltageFilter::new();
+            loop {
+                match read_volts(&mut adc, &mut pin, &config, &mut filter) {
+                    Ok(volts) => {
+                        let battery = status(&config, volts);
+                        info!("Battery {:.2}V {:?}", volts, battery);
+                        if display.send(DisplayMessage::Battery(battery)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("Error reading the battery: {}", e),
+                }
+                thread::sleep(Duration::from_secs(config.interval_secs.max(1) as u64));
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn read_volts(
+        adc: &mut adc::PoweredAdc<adc::ADC1>,
+        pin: &mut Pin,
+        config: &BatteryConfig,
+        filter: &mut VoltageFilter,
+    ) -> Result<f32> {
+        let mut samples = [0.0; SAMPLES];
+        for sample in samples.iter_mut() {
+            let millivolts: u16 = adc.read(pin).map_err(|e| anyhow!("ADC error: {:?}", e))?;
+            *sample = millivolts as f32 / 1000.0 * config.divider;
+        }
+
+        filter
+            .push(&mut samples)
+            .ok_or_else(|| anyhow!("No battery samples"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVE: [(f32, f32); 3] = [(3.3, 0.0), (3.7, 20.0), (4.2, 100.0)];
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn percent_is_interpolated_along_the_curve() {
+        assert_near(percent(&CURVE, 3.3), 0.0);
+        assert_near(percent(&CURVE, 3.5), 10.0);
+        assert_near(percent(&CURVE, 3.7), 20.0);
+        assert_near(percent(&CURVE, 3.95), 60.0);
+        assert_near(percent(&CURVE, 4.2), 100.0);
+    }
+
+    #[test]
+    fn percent_is_clamped_to_the_curve() {
+        assert_near(percent(&CURVE, 2.9), 0.0);
+        assert_near(percent(&CURVE, 5.0), 100.0);
+        assert_near(percent(&[], 3.7), 0.0);
+        assert_near(percent(&[(3.7, 50.0)], 3.9), 50.0);
+        // Repeated points don't divide by zero
+        let steep = [(3.3, 0.0), (3.7, 20.0), (3.7, 30.0), (4.2, 100.0)];
+        assert_near(percent(&steep, 3.7), 20.0);
+        assert_near(percent(&steep, 3.95), 65.0);
+    }
+
+    #[test]
+    fn usb_supply_is_charging() {
+        let config = BatteryConfig::default();
+        assert!(!status(&config, 4.2).charging);
+        assert!(status(&config, 4.8).charging);
+        assert_near(status(&config, 4.8).percent, 100.0);
+    }
+
+    #[test]
+    fn median_drops_the_spikes() {
+        let mut filter = VoltageFilter::new();
+        assert_eq!(filter.push(&mut []), None);
+        assert_eq!(filter.push(&mut [3.8, 0.0, 3.9, 3.7, 6.6]), Some(3.8));
+    }
+
+    #[test]
+    fn noise_is_averaged() {
+        let mut filter = VoltageFilter::new();
+        filter.push(&mut [3.8]);
+        assert_near(filter.push(&mut [3.9, 3.9]).unwrap(), 3.8 + 0.1 * SMOOTHING);
+    }
+
+    #[test]
+    fn jumps_are_followed_right_away() {
+        let mut filter = VoltageFilter::new();
+        filter.push(&mut [3.8]);
+        assert_near(filter.push(&mut [4.8]).unwrap(), 4.8);
+        assert_near(filter.push(&mut [3.7]).unwrap(), 3.7);
+    }
+}
@@ -0,0 +1,176 @@
+/// Time the button level must hold before a change is taken
+const DEBOUNCE_MS: u64 = 30;
+
+/// A press of the button
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Press {
+    /// Released before a long press
+    Short,
+    /// Held for the long press time, sent while still held
+    Long,
+}
+
+/// Turns the raw button level, sampled now and then, into presses: a level
+/// is only taken once it held for `DEBOUNCE_MS`, bouncing contacts are ignored.
+pub struct Debouncer {
+    long_press_ms: u64,
+    /// Debounced level
+    pressed: bool,
+    /// Last raw level and when it was first seen
+    raw: bool,
+    raw_since: u64,
+    /// When the debounced press started, until a long press is sent
+    pressed_since: Option<u64>,
+}
+
+impl Debouncer {
+    pub fn new(long_press_ms: u64) -> Self {
+        Debouncer {
+            long_press_ms,
+            pressed: false,
+            raw: false,
+            raw_since: 0,
+            pressed_since: None,
+        }
+    }
+
+    /// Adds a sample of the raw level at `now_ms`, returns the press it ends
+    pub fn update(&mut self, raw: bool, now_ms: u64) -> Option<Press> {
+        if raw != self.raw {
+            self.raw = raw;
+            self.raw_since = now_ms;
+        }
+
+        if self.raw != self.pressed && now_ms.saturating_sub(self.raw_since) >= DEBOUNCE_MS {
+            self.pressed = self.raw;
+            if self.pressed {
+                self.pressed_since = Some(self.raw_since);
+            } else if self.pressed_since.take().is_some() {
+                return Some(Press::Short);
+            }
+        }
+
+        match self.pressed_since {
+            Some(since) if now_ms.saturating_sub(since) >= self.long_press_ms => {
+                // Nothing more until the button is released
+                self.pressed_since = None;
+                Some(Press::Long)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::input::{enable_wakeup, spawn, woke_up};
+
+/// Polls the button on GPIO0 (the boot button, active low), which can also
+/// wake the device from deep sleep
+#[cfg(target_os = "espidf")]
+mod input {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use embedded_hal::digital::v2::InputPin;
+    use esp_idf_hal::gpio::{Gpio0, Pull, Unknown};
+    use esp_idf_sys::{self as sys, esp};
+    use log::*;
+
+    use super::{Debouncer, Press};
+    use crate::config::ButtonConfig;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    pub fn spawn(pin: Gpio0<Unknown>, config: &ButtonConfig) -> Result<mpsc::Receiver<Press>> {
+        let mut pin = pin.into_input()?;
+        pin.set_pull_up()?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut debouncer = Debouncer::new(config.long_press_ms as u64);
+        let start = Instant::now();
+
+        thread::Builder::new()
+            .stack_size(2048)
+            .spawn(move || loop {
+                let pressed = pin.is_low().unwrap_or(false);
+                if let Some(press) = debouncer.update(pressed, start.elapsed().as_millis() as u64) {
+                    info!("Button {:?} press", press);
+                    if tx.send(press).is_err() {
+                        break;
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+            })?;
+
+        Ok(rx)
+    }
+
+    /// Wakes the device from the next deep sleep when the button is pressed
+    pub fn enable_wakeup() -> Result<()> {
+        esp!(unsafe { sys::esp_sleep_enable_ext0_wakeup(sys::gpio_num_t_GPIO_NUM_0, 0) })?;
+        Ok(())
+    }
+
+    /// Whether the device was woken up from deep sleep by the button
+    pub fn woke_up() -> bool {
+        unsafe {
+            sys::esp_sleep_get_wakeup_cause() == sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_PRESS_MS: u64 = 2000;
+
+    /// Feeds `(level, ms)` samples, returns the presses with their time
+    fn presses(samples: &[(bool, u64)]) -> Vec<(Press, u64)> {
+        let mut debouncer = Debouncer::new(LONG_PRESS_MS);
+        samples
+            .iter()
+            .filter_map(|&(raw, ms)| debouncer.update(raw, ms).map(|press| (press, ms)))
+            .collect()
+    }
+
+    /// The level every 10 ms, `true` between `down` and `up`
+    fn held(down: u64, up: u64, until: u64) -> Vec<(bool, u64)> {
+        (0..=until / 10)
+            .map(|n| n * 10)
+            .map(|ms| (down <= ms && ms < up, ms))
+            .collect()
+    }
+
+    #[test]
+    fn short_press_on_release() {
+        assert_eq!(presses(&held(100, 300, 1000)), vec![(Press::Short, 330)]);
+    }
+
+    #[test]
+    fn long_press_while_held() {
+        assert_eq!(
+            presses(&held(100, 5000, 6000)),
+            vec![(Press::Long, 100 + LONG_PRESS_MS)]
+        );
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let mut samples = held(100, 300, 1000);
+        // Contacts bouncing when pressed and released
+        for (level, ms) in samples.iter_mut() {
+            if matches!(*ms, 110 | 300 | 320) {
+                *level = !*level;
+            }
+        }
+        // A spike shorter than the debounce time
+        samples.push((true, 1010));
+        samples.push((false, 1020));
+        samples.push((false, 1100));
+
+        assert_eq!(presses(&samples), vec![(Press::Short, 360)]);
+    }
+}
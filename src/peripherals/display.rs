@@ -1,133 +1,333 @@
-use anyhow::Result;
-
-use crate::get_time;
-
-use embedded_graphics::image::ImageRaw;
-use embedded_graphics::mono_font::iso_8859_1::{FONT_5X7, FONT_9X18_BOLD};
+#[cfg(any(target_os = "espidf", feature = "simulator", test))]
+pub mod backend;
+#[cfg(all(target_os = "espidf", not(feature = "ttgo")))]
+pub mod eink;
+#[cfg(any(target_os = "espidf", feature = "simulator", test))]
+pub mod refresh;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(test)]
+mod snapshot;
+#[cfg(all(target_os = "espidf", feature = "ttgo"))]
+pub mod ttgo;
+#[cfg(any(
+    all(
+        target_os = "espidf",
+        not(any(feature = "ttgo", feature = "epd2in9bc"))
+    ),
+    test
+))]
+pub mod window;
+
+use embedded_graphics::image::{Image, ImageRaw};
+use embedded_graphics::mono_font::iso_8859_1::{FONT_10X20, FONT_5X7, FONT_6X10, FONT_9X18_BOLD};
 use embedded_graphics::mono_font::{MonoTextStyle, MonoTextStyleBuilder};
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics::prelude::*;
-use embedded_graphics::primitives::{Line, PrimitiveStyle};
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
 use embedded_graphics::text::*;
 
-use esp_idf_hal::prelude::*;
-use esp_idf_hal::{delay, gpio, spi};
+use std::marker::PhantomData;
+use std::time::Duration;
 
-#[cfg(feature = "ttgo")]
-use st7789;
+use time::OffsetDateTime;
+
+use crate::clock::TimeZone;
+use crate::config::Config;
+use crate::emtmadrid::{ArrivalTime, Arrivals, NO_ESTIMATE};
+use crate::peripherals::battery::BatteryStatus;
+
+/// Size of the 3.7" e-ink panel once rotated to landscape, the host backends
+/// render at the same size
+#[cfg(not(target_os = "espidf"))]
+pub const WIDTH: u32 = 480;
+#[cfg(not(target_os = "espidf"))]
+pub const HEIGHT: u32 = 280;
+
+/// Colors of the host backends, set pixels are shown black like on the e-ink
+#[cfg(not(target_os = "espidf"))]
+pub const MONO_PALETTE: Palette<BinaryColor> = Palette {
+    background: BinaryColor::Off,
+    text: BinaryColor::On,
+    header: BinaryColor::On,
+    eta: BinaryColor::On,
+    missed: BinaryColor::On,
+};
+
+// Panels shorter than this get the compact layout: a smaller font, text
+// headers and a one line bus timeline
+const COMPACT_HEIGHT: u32 = 200;
+// Panels at least this wide get a bigger font
+const LARGE_WIDTH: u32 = 640;
+// Height of the one line bus timeline of the compact layout
+const COMPACT_TIMELINE_HEIGHT: i32 = 12;
 
-//#[cfg(esp32s3)]
-use epd_waveshare::{color::*, epd3in7::*, prelude::*};
+#[derive(Debug)]
+pub enum DisplayMessage {
+    Arrivals(Arrivals),
+    Message(String),
+    Battery(BatteryStatus),
+    WiFi(WifiStatus),
+    /// Whether the clock can be trusted, an unsynced one is shown with a '?'
+    TimeSynced(bool),
+    Clear,
+    Update,
+    /// Shows the current frame again with a full refresh, clearing any e-ink
+    /// ghosting
+    FullRefresh,
+    /// Puts the display to sleep before a deep sleep, nothing is drawn after
+    Sleep,
+}
 
-use std;
-use std::sync::mpsc;
-use std::time::Duration;
+/// State of the Wi-Fi link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WifiStatus {
+    /// Connected, with the signal strength of the access point (RSSI) in dBm
+    Connected(f32),
+    /// Waiting to reconnect
+    Offline,
+}
 
-use crate::emtmadrid::ArrivalTime;
-
-#[cfg(feature = "ttgo")]
-pub fn start(
-    backlight: gpio::Gpio4<gpio::Unknown>,
-    dc: gpio::Gpio16<gpio::Unknown>,
-    rst: gpio::Gpio23<gpio::Unknown>, // TTGO ESP32
-    spi: spi::SPI2,
-    sclk: gpio::Gpio18<gpio::Unknown>,
-    sdo: gpio::Gpio19<gpio::Unknown>,
-    cs: gpio::Gpio5<gpio::Unknown>,
-) -> Result<mpsc::SyncSender<String>> {
-    let config = <spi::config::Config as Default>::default().baudrate(26.MHz().into());
-
-    let di = SPIInterfaceNoCS::new(
-        spi::Master::<spi::SPI2, _, _, _, _>::new(
-            spi,
-            spi::Pins {
-                sclk,
-                sdo,
-                sdi: Option::<gpio::Gpio21<gpio::Unknown>>::None,
-                cs: Some(cs),
-            },
-            config,
-        )?,
-        dc.into_output()?,
-    );
+/// Colors the screens are drawn with, each display backend picks its own
+#[derive(Debug, Clone, Copy)]
+pub struct Palette<C> {
+    pub background: C,
+    /// Messages and the arrivals table
+    pub text: C,
+    /// Header, clock and icons
+    pub header: C,
+    /// Expected times at the destinations
+    pub eta: C,
+    /// Rows of the buses that can't be caught anymore
+    pub missed: C,
+}
+
+struct GraphicAssets<'a, C> {
+    battery: [ImageRaw<'a, BinaryColor>; 5],
+    school: ImageRaw<'a, BinaryColor>,
+    work: ImageRaw<'a, BinaryColor>,
+    bus: ImageRaw<'a, BinaryColor>,
+    palette: Palette<C>,
+    /// Small panel, no room for the icons
+    compact: bool,
+    font: MonoTextStyle<'a, C>,
+    header_font: MonoTextStyle<'a, C>,
+    eta_font: MonoTextStyle<'a, C>,
+    font_striket: MonoTextStyle<'a, C>,
+    mini_font: MonoTextStyle<'a, C>,
+}
+
+impl<'a, C> GraphicAssets<'a, C> {
+    fn icon(&self, name: &str) -> Option<&ImageRaw<'a, BinaryColor>> {
+        match name {
+            "school" => Some(&self.school),
+            "work" => Some(&self.work),
+            "bus" => Some(&self.bus),
+            _ => None,
+        }
+    }
+}
+
+/// Turns `DisplayMessage`s into drawing operations, shared by every display
+/// backend (and the host simulator) so they all show the same screens.
+pub struct Renderer<'a, C> {
+    assets: GraphicAssets<'a, C>,
+    config: Config,
+    timezone: TimeZone,
+    clock: fn() -> OffsetDateTime,
+    status: Status,
+    y: i32,
+}
 
-    let mut display = st7789::ST7789::new(di, rst.into_output()?, 240, 320);
+/// Last battery and Wi-Fi readings, shown in the corner of every screen
+#[derive(Debug, Clone, Copy, Default)]
+struct Status {
+    battery: Option<BatteryStatus>,
+    wifi: Option<WifiStatus>,
+    time_synced: Option<bool>,
+}
 
-    display
-        .init(&mut delay::Ets)
-        .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
-    display
-        .set_orientation(st7789::Orientation::Landscape)
-        .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+impl<'a, C: PixelColor> Renderer<'a, C> {
+    /// Creates a renderer for a display of `size` pixels, the layout adapts
+    /// to it
+    pub fn new(config: Config, palette: Palette<C>, size: Size) -> Self {
+        Renderer::with_clock(config, palette, size, OffsetDateTime::now_utc)
+    }
 
-    let top_left = Point::new(40, 53);
+    /// Creates a renderer taking the current time from `clock`, shown in the
+    /// configured time zone
+    pub fn with_clock(
+        config: Config,
+        palette: Palette<C>,
+        size: Size,
+        clock: fn() -> OffsetDateTime,
+    ) -> Self {
+        let assets = load_graphic_assets(palette, size);
+        let y = assets.font.font.character_size.height as i32;
+
+        Renderer {
+            assets,
+            timezone: config.timezone(),
+            config,
+            clock,
+            status: Status::default(),
+            y,
+        }
+    }
 
-    display.clear(Rgb565::BLACK.into()).unwrap();
+    fn now(&self) -> OffsetDateTime {
+        self.timezone.to_local((self.clock)())
+    }
 
-    let mut backlight = backlight.into_output()?;
-    backlight.set_high()?;
+    /// Draws `msg` on `display`, returns true when the frame is complete and
+    /// must be pushed to the panel.
+    pub fn draw<D>(&mut self, display: &mut D, msg: DisplayMessage) -> Result<bool, D::Error>
+    where
+        D: DrawTarget<Color = C>,
+    {
+        let font_height = self.assets.font.font.character_size.height as i32;
+
+        match msg {
+            DisplayMessage::Clear => {
+                clear_display(display, &self.assets, &self.config, self.status, self.now())?;
+                self.y = font_height;
+            }
 
-    let (tx, rx) = mpsc::sync_channel::<String>(5);
+            DisplayMessage::Update => return Ok(true),
 
-    let _ = std::thread::Builder::new()
-        .stack_size(16_000)
-        .spawn(move || {
-            let style = MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE.into());
-            let height = 12;
-            let mut y = height;
-            display.clear(Rgb565::BLACK.into()).unwrap();
+            DisplayMessage::Message(msg) => {
+                Text::new(&msg, Point::new(0, self.y), self.assets.font).draw(display)?;
+                self.y += font_height;
+            }
 
-            for msg in rx {
-                let display = &mut display.translated(top_left);
-                println!("Display: {}", msg);
+            DisplayMessage::Arrivals(arrivals) => {
+                // Counted down to now, the arrivals may be redrawn between fetches
+                let arrivals = arrivals.at((self.clock)());
+                draw_arrivals(
+                    display,
+                    &self.assets,
+                    &self.config,
+                    &arrivals,
+                    &self.timezone,
+                    (self.clock)(),
+                )?;
+                draw_buses(display, &self.assets, &arrivals)?;
+            }
 
-                if msg == "" {
-                    display.clear(Rgb565::BLACK.into()).unwrap();
-                    y = height;
-                    continue;
-                }
+            DisplayMessage::Battery(battery) => {
+                self.status.battery = Some(battery);
+                draw_status(display, &self.assets, self.status)?;
+            }
 
-                if y > 135 {
-                    y = height;
-                    display.clear(Rgb565::BLACK.into()).unwrap();
-                }
-                Text::new(&msg, Point::new(0, y), style)
-                    .draw(display)
-                    .unwrap();
-                y += height;
+            DisplayMessage::TimeSynced(synced) => {
+                // Shown with the clock on the next screen
+                self.status.time_synced = Some(synced);
+            }
+
+            DisplayMessage::WiFi(wifi) => {
+                self.status.wifi = Some(wifi);
+                draw_status(display, &self.assets, self.status)?;
             }
-            display.clear(Rgb565::BLUE.into()).unwrap();
-            thread::sleep(Duration::from_millis(1000));
 
-            display.hard_reset(&mut delay::Ets).unwrap();
-            backlight.set_low().unwrap();
-        });
+            others => {
+                println!("Display: {:?}", others);
+            }
+        }
 
-    Ok(tx)
+        Ok(false)
+    }
 }
 
-#[derive(Debug)]
-pub enum DisplayMessage {
-    Arrivals(Vec<ArrivalTime>),
-    Message(String),
-    Battery(f32),
-    WiFi(f32),
-    Clear,
-    Update,
+/// Adapts a draw target to another color type, `map` picks the color each
+/// source color is shown as. Used to draw the 1-bit icons in palette colors.
+struct Recolor<'a, D, F, C> {
+    target: &'a mut D,
+    map: F,
+    color: PhantomData<C>,
 }
 
-struct GraphicAssets<'a> {
-    battery: [ImageRaw<'a, BinaryColor>; 5],
-    school: ImageRaw<'a, BinaryColor>,
-    work: ImageRaw<'a, BinaryColor>,
-    bus: ImageRaw<'a, BinaryColor>,
-    font: MonoTextStyle<'a, Color>,
-    font_striket: MonoTextStyle<'a, Color>,
-    mini_font: MonoTextStyle<'a, Color>,
+impl<'a, D, F, C> Recolor<'a, D, F, C> {
+    fn new(target: &'a mut D, map: F) -> Self {
+        Recolor {
+            target,
+            map,
+            color: PhantomData,
+        }
+    }
+}
+
+impl<'a, D, F, C> Dimensions for Recolor<'a, D, F, C>
+where
+    D: Dimensions,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<'a, D, F, C> DrawTarget for Recolor<'a, D, F, C>
+where
+    D: DrawTarget,
+    F: Fn(C) -> D::Color,
+    C: PixelColor,
+{
+    type Color = C;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let map = &self.map;
+        self.target.draw_iter(
+            pixels
+                .into_iter()
+                .map(|Pixel(point, color)| Pixel(point, map(color))),
+        )
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.target.clear((self.map)(color))
+    }
 }
 
-fn load_graphic_assets<'a>() -> GraphicAssets<'a> {
-    GraphicAssets::<'a> {
+/// Draws a 1-bit icon at `position`, set pixels in `color` and the rest in the
+/// background color
+fn draw_icon<D, C>(
+    display: &mut D,
+    icon: &ImageRaw<BinaryColor>,
+    position: Point,
+    color: C,
+    background: C,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
+{
+    let mut target = Recolor::new(
+        display,
+        |c: BinaryColor| {
+            if c.is_on() {
+                color
+            } else {
+                background
+            }
+        },
+    );
+    Image::new(icon, position).draw(&mut target)
+}
+
+fn load_graphic_assets<'a, C: PixelColor>(palette: Palette<C>, size: Size) -> GraphicAssets<'a, C> {
+    let compact = size.height < COMPACT_HEIGHT;
+    let font = if compact {
+        &FONT_6X10
+    } else if size.width >= LARGE_WIDTH {
+        &FONT_10X20
+    } else {
+        &FONT_9X18_BOLD
+    };
+
+    GraphicAssets::<'a, C> {
         battery: [
             ImageRaw::new_binary(include_bytes!("../../icons/Batt0.raw"), 30),
             ImageRaw::new_binary(include_bytes!("../../icons/Batt1.raw"), 30),
@@ -138,309 +338,474 @@ fn load_graphic_assets<'a>() -> GraphicAssets<'a> {
         school: ImageRaw::new_binary(include_bytes!("../../icons/School.raw"), 30),
         work: ImageRaw::new_binary(include_bytes!("../../icons/Work.raw"), 30),
         bus: ImageRaw::new_binary(include_bytes!("../../icons/Bus2.raw"), 30),
-        font: MonoTextStyle::new(&FONT_9X18_BOLD, Color::Black),
+        palette,
+        compact,
+        font: MonoTextStyle::new(font, palette.text),
+        header_font: MonoTextStyle::new(font, palette.header),
+        eta_font: MonoTextStyle::new(font, palette.eta),
         font_striket: MonoTextStyleBuilder::new()
-            .font(&FONT_9X18_BOLD)
-            .text_color(Color::Black)
-            .strikethrough_with_color(Color::Black)
-            .background_color(Color::White)
+            .font(font)
+            .text_color(palette.missed)
+            .strikethrough_with_color(palette.missed)
+            .background_color(palette.background)
             .build(),
         mini_font: MonoTextStyleBuilder::new()
             .font(&FONT_5X7)
-            .text_color(Color::Black)
-            .background_color(Color::White)
+            .text_color(palette.text)
+            .background_color(palette.background)
             .build(),
     }
 }
 
-pub fn start(
-    busy: gpio::Gpio4<gpio::Unknown>,
-    dc: gpio::Gpio16<gpio::Unknown>,
-    rst: gpio::Gpio13<gpio::Unknown>,
-    spi: spi::SPI2,
-    sclk: gpio::Gpio18<gpio::Unknown>,
-    sdo: gpio::Gpio19<gpio::Unknown>,
-    cs: gpio::Gpio5<gpio::Unknown>,
-) -> Result<mpsc::SyncSender<DisplayMessage>> {
-    let config = <spi::config::Config as Default>::default().baudrate(26.MHz().into());
-
-    println!("Setup eink display SPI interface");
-
-    let mut spi_interface = spi::Master::<spi::SPI2, _, _, _, _>::new(
-        spi,
-        spi::Pins {
-            sclk,
-            sdo,
-            sdi: Option::<gpio::Gpio21<gpio::Unknown>>::None,
-            cs: Option::<gpio::Gpio5<gpio::Unknown>>::None,
-        },
-        config,
-    )?;
+fn clear_display<D, C>(
+    display: &mut D,
+    assets: &GraphicAssets<C>,
+    config: &Config,
+    status: Status,
+    now: OffsetDateTime,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
+{
+    let palette = assets.palette;
+    display.clear(palette.background)?;
+    let font_height = assets.font.font.character_size.height as i32;
+    let font_width = assets.font.font.character_size.width as i32;
 
-    let mut eink = EPD3in7::new(
-        &mut spi_interface,
-        cs.into_output()?,
-        busy.into_input()?,
-        dc.into_output()?,
-        rst.into_output()?,
-        &mut delay::FreeRtos,
-    )?;
+    let total_chars = display.bounding_box().size.width as i32 / font_width;
+    let layout = TableLayout::new(total_chars, config);
 
-    eink.set_lut(&mut spi_interface, Some(RefreshLut::Quick))?;
+    // Narrow displays have no room for the titles under the clock
+    let header: String = format!(
+        " {:>stop$} LINE {:dest$} TIME",
+        "STOP",
+        "",
+        stop = layout.stop_chars,
+        dest = layout.destination_chars
+    )
+    .chars()
+    .take((total_chars.max(0) as usize).saturating_sub(CLOCK_CHARS))
+    .collect();
+
+    Text::new(&header, Point::new(0, font_height), assets.header_font).draw(&mut *display)?;
+
+    for (idx, destination) in config.destinations.iter().take(layout.columns).enumerate() {
+        let x = layout.eta_chars_offset(idx) as i32 * font_width;
+
+        match assets.icon(&destination.icon).filter(|_| !assets.compact) {
+            Some(icon) => {
+                let icon_width = icon.bounding_box().size.width as i32;
+                let x = x + (ETA_CHARS as i32 * font_width - icon_width) / 2;
+                draw_icon(
+                    display,
+                    icon,
+                    Point::new(x, 0),
+                    palette.header,
+                    palette.background,
+                )?;
+            }
+            None => {
+                let name: String = destination.name.chars().take(ETA_CHARS).collect();
+                Text::new(&name, Point::new(x, font_height), assets.header_font)
+                    .draw(&mut *display)?;
+            }
+        }
+    }
 
-    let (tx, rx) = mpsc::sync_channel::<DisplayMessage>(5);
+    draw_status(display, assets, status)?;
 
-    let _ = std::thread::Builder::new()
-        .stack_size(4_000)
-        .spawn(move || {
-            let mut display = Box::new(Display3in7::default());
-            display.clear(Color::Black).unwrap();
-            eink.update_and_display_frame(
-                &mut spi_interface,
-                display.buffer(),
-                &mut delay::FreeRtos,
-            )
-            .unwrap();
-            display.clear(Color::White).unwrap();
-            eink.update_and_display_frame(
-                &mut spi_interface,
-                display.buffer(),
-                &mut delay::FreeRtos,
-            )
-            .unwrap();
-            display.set_rotation(DisplayRotation::Rotate90);
-
-            let assets = load_graphic_assets();
-
-            let font_height = assets.font.font.character_size.height as i32;
-            let mut y = font_height;
-
-            for msg in rx {
-                match msg {
-                    DisplayMessage::Clear => {
-                        clear_display(&mut *display, &assets).unwrap();
-                        y = font_height;
-                        continue;
-                    }
-
-                    DisplayMessage::Update => {
-                        eink.update_and_display_frame(
-                            &mut spi_interface,
-                            display.buffer(),
-                            &mut delay::FreeRtos,
-                        )
-                        .unwrap();
-                        continue;
-                    }
-
-                    DisplayMessage::Message(msg) => {
-                        Text::new(&msg, Point::new(0, y), assets.font)
-                            .draw(&mut *display)
-                            .unwrap();
-                        y += font_height;
-                    }
-
-                    DisplayMessage::Arrivals(arrivals) => {
-                        draw_arrivals(&mut *display, &assets, &arrivals).unwrap();
-                        draw_buses(&mut *display, &assets, &arrivals).unwrap();
-                    }
-
-                    others => {
-                        println!("Display: {:?}", others);
-                    }
-                }
-            }
+    let display_width = display.bounding_box().size.width as i32 - 1;
+    let thin_stroke = PrimitiveStyle::with_stroke(palette.header, 1);
+    Line::new(
+        Point::new(0, font_height + 2),
+        Point::new(display_width, font_height + 2),
+    )
+    .into_styled(thin_stroke)
+    .draw(&mut *display)?;
 
-            // If the msg channel is closed, we should shut down the display and put it to sleep
-            display.clear(Color::White).unwrap();
-            eink.set_lut(&mut spi_interface, Some(RefreshLut::Full))
-                .unwrap();
-            eink.update_and_display_frame(
-                &mut spi_interface,
-                display.buffer(),
-                &mut delay::FreeRtos,
-            )
-            .unwrap();
-            eink.sleep(&mut spi_interface, &mut delay::FreeRtos)
-                .unwrap();
-        });
+    let t = now.time();
+    let mut clock = format!("{:02}:{:02}", t.hour(), t.minute());
+    if status.time_synced == Some(false) {
+        clock.push('?');
+    }
+    Text::new(
+        &clock,
+        Point::new(
+            (display_width as i32 - font_width * clock.len() as i32 - 3) as i32,
+            font_height - 4,
+        ),
+        assets.header_font,
+    )
+    .draw(&mut *display)?;
 
-    Ok(tx)
+    Ok(())
 }
 
-fn clear_display<D>(display: &mut D, assets: &GraphicAssets) -> Result<(), D::Error>
+/// Draws the battery and Wi-Fi indicators in the bottom right corner
+fn draw_status<D, C>(
+    display: &mut D,
+    assets: &GraphicAssets<C>,
+    status: Status,
+) -> Result<(), D::Error>
 where
-    D: DrawTarget<Color = Color>,
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
 {
-    display.clear(Color::White)?;
-    let font_height = assets.font.font.character_size.height as i32;
-    let font_width = assets.font.font.character_size.width as i32;
+    if assets.compact {
+        return draw_compact_status(display, assets, status);
+    }
 
+    let palette = assets.palette;
     let display_height = display.bounding_box().size.height as i32 - 1;
     let display_width = display.bounding_box().size.width as i32 - 1;
-    let batt_height = assets.battery[4].bounding_box().size.height as i32;
-    let batt_width = assets.battery[4].bounding_box().size.width as i32;
+    let icon_size = assets.battery[0].bounding_box().size;
+    let (icon_width, icon_height) = (icon_size.width as i32, icon_size.height as i32);
 
-    let header = " STOP LINE                 TIME     ";
+    // The battery in the corner and the Wi-Fi bars on its left
+    let battery_at = Point::new(display_width - icon_width, display_height - icon_height);
+    let wifi_at = battery_at - Point::new(icon_width, 0);
 
-    Text::new(&header, Point::new(0, font_height), assets.font).draw(&mut *display)?;
+    Rectangle::new(wifi_at, Size::new(icon_size.width * 2, icon_size.height))
+        .into_styled(PrimitiveStyle::with_fill(palette.background))
+        .draw(&mut *display)?;
 
-    assets.school.draw(
-        &mut display
-            .translated(Point::new((header.len() as i32 * font_width).into(), 0))
-            .color_converted(),
-    )?;
-    assets.work.draw(
-        &mut display
-            .translated(Point::new(
-                ((header.len() as i32 + 7) * font_width).into(),
-                0,
-            ))
-            .color_converted(),
-    )?;
-    assets.battery[4].draw(
-        &mut display
-            .translated(Point::new(
-                display_width - batt_width,
-                display_height - batt_height,
-            ))
-            .color_converted(),
+    // Until the first reading the full battery stays as a placeholder
+    let battery_level = status.battery.map_or(100.0, |b| b.percent);
+    draw_icon(
+        display,
+        &assets.battery[battery_icon(battery_level)],
+        battery_at,
+        palette.header,
+        palette.background,
     )?;
 
+    if status.battery.map_or(false, |b| b.charging) {
+        let mini_height = assets.mini_font.font.character_size.height as i32;
+        Text::new(
+            "+",
+            battery_at + Point::new(0, mini_height),
+            assets.mini_font,
+        )
+        .draw(&mut *display)?;
+    }
+
+    if let Some(wifi) = status.wifi {
+        let bars = match wifi {
+            WifiStatus::Connected(rssi) => wifi_bars(rssi),
+            WifiStatus::Offline => 0,
+        };
+        let bar_width = (icon_width - 2) / WIFI_BARS as i32 - 2;
+        for bar in 0..WIFI_BARS {
+            let height = (icon_height - 2) * (bar as i32 + 1) / WIFI_BARS as i32;
+            let top_left =
+                wifi_at + Point::new(2 + bar as i32 * (bar_width + 2), icon_height - height);
+            // Bars above the signal strength are only outlined
+            let style = if bar < bars {
+                PrimitiveStyle::with_fill(palette.header)
+            } else {
+                PrimitiveStyle::with_stroke(palette.header, 1)
+            };
+            Rectangle::new(top_left, Size::new(bar_width as u32, height as u32))
+                .into_styled(style)
+                .draw(&mut *display)?;
+        }
+
+        // Crossed out while offline
+        if wifi == WifiStatus::Offline {
+            let stroke = PrimitiveStyle::with_stroke(palette.header, 2);
+            let size = Point::new(icon_width - 1, icon_height - 1);
+            Line::new(wifi_at, wifi_at + size)
+                .into_styled(stroke)
+                .draw(&mut *display)?;
+            Line::new(
+                wifi_at + Point::new(size.x, 0),
+                wifi_at + Point::new(0, size.y),
+            )
+            .into_styled(stroke)
+            .draw(&mut *display)?;
+        }
+    }
+    Ok(())
+}
+
+/// Draws small battery and Wi-Fi indicators at the end of the bus timeline,
+/// the icons don't fit on compact layouts
+fn draw_compact_status<D, C>(
+    display: &mut D,
+    assets: &GraphicAssets<C>,
+    status: Status,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
+{
+    let palette = assets.palette;
+    let display_height = display.bounding_box().size.height as i32 - 1;
     let display_width = display.bounding_box().size.width as i32 - 1;
-    let thin_stroke = PrimitiveStyle::with_stroke(Color::Black, 1);
-    Line::new(
-        Point::new(0, font_height + 2),
-        Point::new(display_width, font_height + 2),
+    let (body_width, height) = (COMPACT_BATTERY.width as i32, COMPACT_BATTERY.height as i32);
+
+    // Above the road, the battery body and its tip in the corner, room for a
+    // '+' when charging and the Wi-Fi bars on their left
+    let top = display_height - 1 - height;
+    let battery_at = Point::new(display_width - body_width - 1, top);
+    let status_at = Point::new(display_width - COMPACT_STATUS_WIDTH as i32 + 1, top);
+
+    Rectangle::new(
+        status_at,
+        Size::new(COMPACT_STATUS_WIDTH, COMPACT_BATTERY.height),
     )
-    .into_styled(thin_stroke)
+    .into_styled(PrimitiveStyle::with_fill(palette.background))
     .draw(&mut *display)?;
 
-    let t = get_time().time();
-    let clock = format!("{:02}:{:02}", t.hour(), t.minute());
-    Text::new(
-        &clock,
-        Point::new(
-            (display_width as i32 - font_width * 5 - 3) as i32,
-            font_height - 4,
-        ),
-        assets.font,
+    // Until the first reading the full battery stays as a placeholder
+    let battery_level = status.battery.map_or(100.0, |b| b.percent);
+    let fill = PrimitiveStyle::with_fill(palette.header);
+    Rectangle::new(battery_at, COMPACT_BATTERY)
+        .into_styled(PrimitiveStyle::with_stroke(palette.header, 1))
+        .draw(&mut *display)?;
+    Rectangle::new(
+        battery_at + Point::new(body_width, height / 2 - 1),
+        Size::new(1, 3),
+    )
+    .into_styled(fill)
+    .draw(&mut *display)?;
+    // A quarter of the inside per level, like the battery icons
+    let level = battery_icon(battery_level) as i32;
+    Rectangle::new(
+        battery_at + Point::new(2, 2),
+        Size::new((level * (body_width - 4) / 4) as u32, height as u32 - 4),
     )
+    .into_styled(fill)
     .draw(&mut *display)?;
 
+    let mini_width = assets.mini_font.font.character_size.width as i32;
+    if status.battery.map_or(false, |b| b.charging) {
+        Text::new(
+            "+",
+            battery_at + Point::new(-mini_width - 1, height - 1),
+            assets.mini_font,
+        )
+        .draw(&mut *display)?;
+    }
+
+    if let Some(wifi) = status.wifi {
+        let bars = match wifi {
+            WifiStatus::Connected(rssi) => wifi_bars(rssi),
+            WifiStatus::Offline => 0,
+        };
+        for bar in 0..WIFI_BARS {
+            let bar_height = height * (bar as i32 + 1) / WIFI_BARS as i32;
+            let top_left = status_at + Point::new(bar as i32 * 4, height - bar_height);
+            // Bars above the signal strength are only outlined
+            let style = if bar < bars {
+                fill
+            } else {
+                PrimitiveStyle::with_stroke(palette.header, 1)
+            };
+            Rectangle::new(top_left, Size::new(3, bar_height as u32))
+                .into_styled(style)
+                .draw(&mut *display)?;
+        }
+
+        // Crossed out while offline
+        if wifi == WifiStatus::Offline {
+            let stroke = PrimitiveStyle::with_stroke(palette.header, 1);
+            let size = Point::new(WIFI_BARS as i32 * 4 - 2, height - 1);
+            Line::new(status_at, status_at + size)
+                .into_styled(stroke)
+                .draw(&mut *display)?;
+            Line::new(
+                status_at + Point::new(size.x, 0),
+                status_at + Point::new(0, size.y),
+            )
+            .into_styled(stroke)
+            .draw(&mut *display)?;
+        }
+    }
     Ok(())
 }
 
-struct LineInfo<'a> {
-    name: &'a str,
-    seconds_from_home: u32,
-    seconds_to_school: u32,
-    seconds_to_work: u32,
+/// Picks among the five battery icons, from empty to full
+fn battery_icon(level: f32) -> usize {
+    ((level.max(0.0) + 12.5) / 25.0).min(4.0) as usize
+}
+
+const WIFI_BARS: usize = 4;
+
+/// Battery body drawn on compact layouts, its tip adds a column
+const COMPACT_BATTERY: Size = Size::new(12, 7);
+/// Wi-Fi bars, a '+' while charging and the battery of compact layouts
+const COMPACT_STATUS_WIDTH: u32 = 35;
+
+/// Number of Wi-Fi bars shown for a signal strength in dBm
+fn wifi_bars(rssi: f32) -> usize {
+    match rssi {
+        rssi if rssi >= -55.0 => 4,
+        rssi if rssi >= -65.0 => 3,
+        rssi if rssi >= -75.0 => 2,
+        rssi if rssi >= -85.0 => 1,
+        _ => 0,
+    }
+}
+
+// Widths in characters of the arrivals table
+const ROW_FIXED_CHARS: usize = 13; // line, time and separators
+const MIN_STOP_CHARS: usize = 4;
+const MAX_STOP_CHARS: usize = 8;
+const DESTINATION_CHARS: usize = 15;
+const MIN_DESTINATION_CHARS: usize = 8;
+const ETA_CHARS: usize = 5;
+const MIN_COLUMN_CHARS: usize = ETA_CHARS + 1;
+const MAX_COLUMN_CHARS: usize = ETA_CHARS + 3;
+const CLOCK_CHARS: usize = 6;
+
+/// Columns of the arrivals table, laid out for the display width and the
+/// number of configured destinations.
+struct TableLayout {
+    /// Width of the stop label, enough for the longest one that fits
+    stop_chars: usize,
+    /// Width of the bus destination text
+    destination_chars: usize,
+    /// Width of each destination ETA column
+    column_chars: usize,
+    /// Number of destination columns that fit
+    columns: usize,
 }
 
-const SECONDS_TO_874: u32 = 5 * 60;
-const SECONDS_TO_1455: u32 = 3 * 60;
-
-const LINE_INFO: [LineInfo; 6] = [
-    LineInfo {
-        name: "31",
-        seconds_from_home: SECONDS_TO_874,
-        seconds_to_school: (4 + 4) * 60,
-        seconds_to_work: (8 + 8) * 60,
-    },
-    LineInfo {
-        name: "33",
-        seconds_from_home: SECONDS_TO_874,
-        seconds_to_school: (5 + 6) * 60,
-        seconds_to_work: 0,
-    },
-    LineInfo {
-        name: "36",
-        seconds_from_home: SECONDS_TO_874,
-        seconds_to_school: (5 + 1) * 60,
-        seconds_to_work: 0,
-    },
-    LineInfo {
-        name: "39",
-        seconds_from_home: SECONDS_TO_874,
-        seconds_to_school: (5 + 6) * 60,
-        seconds_to_work: (7 + 7) * 60,
-    },
-    LineInfo {
-        name: "65",
-        seconds_from_home: SECONDS_TO_874,
-        seconds_to_school: (4 + 4) * 60,
-        seconds_to_work: (8 + 8) * 60,
-    },
-    LineInfo {
-        name: "138",
-        seconds_from_home: SECONDS_TO_1455,
-        seconds_to_school: (6 + 6) * 60,
-        seconds_to_work: (12 + 7) * 60,
-    },
-];
-
-fn get_line_info(line: &str) -> &LineInfo {
-    for l in LINE_INFO.iter() {
-        if l.name == line {
-            return l;
+impl TableLayout {
+    fn new(total_chars: i32, config: &Config) -> Self {
+        let total_chars = total_chars.max(0) as usize;
+        let destinations = config.destinations.len();
+        let stop_chars = config
+            .stops
+            .iter()
+            .map(|s| s.label.chars().count())
+            .max()
+            .unwrap_or(0)
+            .clamp(MIN_STOP_CHARS, MAX_STOP_CHARS);
+        let available = |destination_chars: usize| {
+            total_chars
+                .saturating_sub(ROW_FIXED_CHARS + stop_chars + destination_chars + CLOCK_CHARS)
+        };
+
+        // Shorten the bus destination text before dropping ETA columns
+        let mut destination_chars = DESTINATION_CHARS;
+        while available(destination_chars) < destinations * MIN_COLUMN_CHARS
+            && destination_chars > MIN_DESTINATION_CHARS
+        {
+            destination_chars -= 1;
+        }
+
+        let columns = destinations.min(available(destination_chars) / MIN_COLUMN_CHARS);
+        let column_chars = match columns {
+            0 => MIN_COLUMN_CHARS,
+            n => (available(destination_chars) / n).min(MAX_COLUMN_CHARS),
+        };
+
+        TableLayout {
+            stop_chars,
+            destination_chars,
+            column_chars,
+            columns,
         }
     }
-    &LineInfo {
-        name: "??",
-        seconds_from_home: 0,
-        seconds_to_school: 0,
-        seconds_to_work: 0,
+
+    /// Offset of the ETA text of a destination column from the row start
+    fn eta_chars_offset(&self, column: usize) -> usize {
+        ROW_FIXED_CHARS
+            + self.stop_chars
+            + self.destination_chars
+            + (column + 1) * self.column_chars
+            - ETA_CHARS
+    }
+}
+
+/// `text` in at most `width` characters, ending in "." when cut
+fn fit(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
     }
+    let mut fitted: String = text.chars().take(width.saturating_sub(1)).collect();
+    fitted.push('.');
+    fitted
 }
 
-fn draw_arrivals<D>(
+fn draw_arrivals<D, C>(
     display: &mut D,
-    assets: &GraphicAssets,
+    assets: &GraphicAssets<C>,
+    config: &Config,
     arrivals: &Vec<ArrivalTime>,
+    timezone: &TimeZone,
+    now: OffsetDateTime,
 ) -> Result<(), D::Error>
 where
-    D: DrawTarget<Color = Color>,
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
 {
     let font_height = assets.font.font.character_size.height as i32;
+    let font_width = assets.font.font.character_size.width as i32;
     let mut y = font_height * 2 + 2;
 
+    let layout = TableLayout::new(
+        display.bounding_box().size.width as i32 / font_width,
+        config,
+    );
+
     for arrival in arrivals {
         let t_str = time_string(arrival);
-        let t_now = get_time();
-        let mut t_at_school = String::from("");
-        let mut t_at_work = String::from("");
-        let line_info = get_line_info(&arrival.line);
-
-        if arrival.time < 19999 {
-            if line_info.seconds_to_school != 0 {
-                let school_time = arrival.time as u32 + line_info.seconds_to_school;
-                let t = t_now + Duration::from_secs(school_time as u64);
-                t_at_school = format!("{:02}:{:02}", t.hour(), t.minute());
-            }
-
-            if line_info.seconds_to_work != 0 {
-                let work_time = arrival.time as u32 + line_info.seconds_to_work;
-                let t = t_now + Duration::from_secs(work_time as u64);
-                t_at_work = format!("{:02}:{:02}", t.hour(), t.minute());
-            }
-        }
-
+        let stop = config.stop(&arrival.stop);
+        let line_info = config.line(&arrival.line);
+
+        // Without a walking time there is no telling whether it can be caught
+        let label = match stop {
+            Some(stop) => fit(&stop.label, layout.stop_chars),
+            None => fit(&arrival.stop, layout.stop_chars - 1) + "?",
+        };
         let line = format!(
-            "{:>4} {:>3} {:15} {:5}   {:5}   {:5}",
-            arrival.stop, arrival.line, arrival.destination, t_str, t_at_school, t_at_work,
+            "{:>stop$} {:>3} {:dest$} {:7}",
+            label,
+            arrival.line,
+            fit(&arrival.destination, layout.destination_chars),
+            t_str,
+            stop = layout.stop_chars,
+            dest = layout.destination_chars,
         );
 
-        if arrival.time > line_info.seconds_from_home.into() {
+        let mut etas = String::new();
+        for destination in config.destinations.iter().take(layout.columns) {
+            // Lines we know nothing about only get the arrival time
+            let eta = match line_info.and_then(|l| l.seconds_to(&destination.name)) {
+                Some(ride_time) if arrival.time < NO_ESTIMATE => {
+                    // Local time when getting there, may be past a DST change
+                    let t = timezone
+                        .to_local(now + Duration::from_secs(arrival.time + ride_time as u64));
+                    format!("{:02}:{:02}", t.hour(), t.minute())
+                }
+                _ => String::new(),
+            };
+            etas.push_str(&format!("{:>width$}", eta, width = layout.column_chars));
+        }
+
+        // Strike out the buses we can't catch walking to their stop
+        if stop.map_or(true, |s| arrival.time > s.seconds_from_home.into()) {
+            let etas_x = line.chars().count() as i32 * font_width;
             Text::new(&line, Point::new(0, y), assets.font).draw(&mut *display)?;
+            Text::new(&etas, Point::new(etas_x, y), assets.eta_font).draw(&mut *display)?;
         } else {
-            Text::new(&line, Point::new(0, y), assets.font_striket).draw(&mut *display)?;
+            Text::new(&(line + &etas), Point::new(0, y), assets.font_striket)
+                .draw(&mut *display)?;
         }
 
+        // Leave room for the "..." and the bus timeline
+        let reserved = if assets.compact {
+            font_height + COMPACT_TIMELINE_HEIGHT
+        } else {
+            (assets.bus.bounding_box().size.height as i32) * 2
+        };
+
         y += font_height;
-        if y >= display.bounding_box().size.height as i32
-            - (assets.bus.bounding_box().size.height as i32) * 2
-        {
+        if y >= display.bounding_box().size.height as i32 - reserved {
             Text::new(&"...", Point::new(0, y - 5), assets.font).draw(&mut *display)?;
             break;
         }
@@ -448,18 +813,37 @@ where
     Ok(())
 }
 
-fn draw_buses<D>(
+fn draw_buses<D, C>(
     display: &mut D,
-    assets: &GraphicAssets,
+    assets: &GraphicAssets<C>,
     arrivals: &Vec<ArrivalTime>,
 ) -> Result<(), D::Error>
 where
-    D: DrawTarget<Color = Color>,
+    D: DrawTarget<Color = C>,
+    C: PixelColor,
 {
     let display_height = display.bounding_box().size.height as i32 - 1;
-    let display_width = display.bounding_box().size.width as i32 - 1 - (30 * 3); // leave room for bus and , stop and battery icon
+    let mini_width = assets.mini_font.font.character_size.width as i32;
+    // leave room for bus and , stop and battery icon (or the bus number and
+    // the status)
+    let reserved = if assets.compact {
+        mini_width * 4 + COMPACT_STATUS_WIDTH as i32
+    } else {
+        30 * 3
+    };
+    let display_width = display.bounding_box().size.width as i32 - 1 - reserved;
     let bus_height = assets.bus.bounding_box().size.height as i32;
 
+    if assets.compact {
+        // A road with the bus numbers on it instead of the icons
+        Line::new(
+            Point::new(0, display_height),
+            Point::new(display_width + reserved, display_height),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(assets.palette.text, 1))
+        .draw(&mut *display)?;
+    }
+
     for arrival in arrivals {
         let max_time = 12 * 60 as i32;
         let t = arrival.time as i32;
@@ -468,10 +852,22 @@ where
         }
         let x = display_width - (t * display_width / max_time) as i32;
 
-        assets.bus.draw(
-            &mut display
-                .translated(Point::new(x, display_height - bus_height))
-                .color_converted(),
+        if assets.compact {
+            Text::new(
+                &arrival.line,
+                Point::new(x, display_height - 4),
+                assets.mini_font,
+            )
+            .draw(&mut *display)?;
+            continue;
+        }
+
+        draw_icon(
+            display,
+            &assets.bus,
+            Point::new(x, display_height - bus_height),
+            assets.palette.text,
+            assets.palette.background,
         )?;
         Text::new(
             &format!("{}", &arrival.line),
@@ -486,7 +882,7 @@ where
 fn time_string(arrival: &ArrivalTime) -> String {
     if arrival.time == 0 {
         return String::from(">>>>>>>");
-    } else if arrival.time > 19999 {
+    } else if arrival.time > NO_ESTIMATE {
         return String::from("      ");
     }
 
@@ -494,3 +890,27 @@ fn time_string(arrival: &ArrivalTime) -> String {
     let time_s = arrival.time % 60;
     return format!("{:>2}m {:02}s", time_m, time_s);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_text_ends_in_a_dot() {
+        assert_eq!(fit("Sol", 4), "Sol");
+        assert_eq!(fit("Casa", 4), "Casa");
+        assert_eq!(fit("Oficina", 4), "Ofi.");
+    }
+
+    #[test]
+    fn stop_column_fits_the_longest_label() {
+        let mut config = Config::default();
+        assert_eq!(TableLayout::new(53, &config).stop_chars, MIN_STOP_CHARS);
+
+        config.stops[0].label = "Oficina".to_string();
+        assert_eq!(TableLayout::new(53, &config).stop_chars, 7);
+
+        config.stops[0].label = "Plaza de Castilla".to_string();
+        assert_eq!(TableLayout::new(53, &config).stop_chars, MAX_STOP_CHARS);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use embedded_graphics::prelude::*;
+use log::*;
+
+use super::refresh::RefreshPolicy;
+use super::{DisplayMessage, Palette, Renderer};
+use crate::config::Config;
+
+/// How a frame is pushed to the panel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refresh {
+    /// Clean refresh, slow and flashing on e-ink panels
+    Full,
+    /// Fast refresh, may leave some ghosting on e-ink panels
+    Partial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    /// Black and white
+    Mono,
+    /// Black, white and one accent color
+    TriColor,
+    /// 16 bit RGB
+    Rgb565,
+}
+
+/// A screen driver, the shared message loop draws every screen into its
+/// target and asks it to show the result.
+///
+/// Backends that are also their own `DrawTarget` spell their colors out in
+/// that impl, `Self::Color` would be ambiguous with the one of this trait.
+pub trait DisplayBackend {
+    type Color: PixelColor;
+    /// Where the screens are drawn, a frame buffer or the panel itself
+    type Target: DrawTarget<Color = Self::Color>;
+
+    /// When set, the loop calls `idle` this often while waiting for messages
+    const IDLE_INTERVAL: Option<Duration> = None;
+
+    fn init(&mut self) -> Result<()>;
+
+    fn target(&mut self) -> &mut Self::Target;
+
+    /// Shows what was drawn into the target since the last refresh
+    fn refresh(&mut self, refresh: Refresh) -> Result<()>;
+
+    /// Fraction (0 to 1) of the target changed since the last refresh, for
+    /// backends keeping a copy of what is on the panel
+    fn changed(&self) -> Option<f32> {
+        None
+    }
+
+    /// Puts the panel in its low power state, blanking it unless it keeps the
+    /// image without power like e-ink
+    fn sleep(&mut self) -> Result<()>;
+
+    /// Size of the drawing area in pixels, once rotated
+    fn size(&self) -> Size;
+
+    fn color_depth(&self) -> ColorDepth;
+
+    fn palette(&self) -> Palette<Self::Color>;
+
+    /// Returns false to stop the loop, e.g. when the simulator window is closed
+    fn idle(&mut self) -> bool {
+        true
+    }
+}
+
+/// Draws the messages received on `rx` on `backend` until the channel is
+/// closed or a `Sleep` message arrives, then puts the display to sleep.
+pub fn run<B>(mut backend: B, config: Config, rx: mpsc::Receiver<DisplayMessage>) -> Result<()>
+where
+    B: DisplayBackend,
+    <B::Target as DrawTarget>::Error: Debug,
+{
+    backend.init()?;
+
+    let size = backend.size();
+    info!(
+        "Display {}x{} {:?}",
+        size.width,
+        size.height,
+        backend.color_depth()
+    );
+
+    let mut policy = RefreshPolicy::new(config.refresh.clone());
+    let mut renderer = Renderer::new(config, backend.palette(), size);
+
+    loop {
+        let msg = match B::IDLE_INTERVAL {
+            Some(interval) => match rx.recv_timeout(interval) {
+                Ok(msg) => msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if backend.idle() {
+                        continue;
+                    }
+                    return Ok(());
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
+
+        if let DisplayMessage::Sleep = msg {
+            break;
+        }
+
+        if let DisplayMessage::FullRefresh = msg {
+            policy.request_full();
+            backend.refresh(policy.next(None))?;
+            continue;
+        }
+
+        let update = renderer
+            .draw(backend.target(), msg)
+            .map_err(|e| anyhow!("Display error: {:?}", e))?;
+        if update {
+            let refresh = policy.next(backend.changed());
+            backend.refresh(refresh)?;
+        }
+    }
+
+    backend.sleep()
+}
+
+/// Runs the message loop of `backend` on its own thread
+#[cfg(target_os = "espidf")]
+pub fn spawn<B>(backend: B, config: Config) -> Result<mpsc::SyncSender<DisplayMessage>>
+where
+    B: DisplayBackend + Send + 'static,
+    <B::Target as DrawTarget>::Error: Debug,
+{
+    let (tx, rx) = mpsc::sync_channel::<DisplayMessage>(5);
+
+    std::thread::Builder::new()
+        .stack_size(16_000)
+        .spawn(move || {
+            if let Err(e) = run(backend, config, rx) {
+                error!("Display stopped: {}", e);
+            }
+        })?;
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use embedded_graphics::pixelcolor::BinaryColor;
+
+    use super::*;
+    use crate::config::RefreshConfig;
+
+    /// Draws nowhere and keeps the refreshes it was asked for
+    struct Fake {
+        refreshes: Vec<Refresh>,
+    }
+
+    impl DrawTarget for Fake {
+        type Color = BinaryColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, _pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<BinaryColor>>,
+        {
+            Ok(())
+        }
+    }
+
+    impl OriginDimensions for Fake {
+        fn size(&self) -> Size {
+            Size::new(416, 240)
+        }
+    }
+
+    impl DisplayBackend for &mut Fake {
+        type Color = BinaryColor;
+        type Target = Fake;
+
+        fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn target(&mut self) -> &mut Fake {
+            self
+        }
+
+        fn refresh(&mut self, refresh: Refresh) -> Result<()> {
+            self.refreshes.push(refresh);
+            Ok(())
+        }
+
+        fn sleep(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> Size {
+            OriginDimensions::size(*self)
+        }
+
+        fn color_depth(&self) -> ColorDepth {
+            ColorDepth::Mono
+        }
+
+        fn palette(&self) -> Palette<BinaryColor> {
+            Palette {
+                background: BinaryColor::Off,
+                text: BinaryColor::On,
+                header: BinaryColor::On,
+                eta: BinaryColor::On,
+                missed: BinaryColor::On,
+            }
+        }
+    }
+
+    #[test]
+    fn full_refresh_on_demand() {
+        let config = Config {
+            refresh: RefreshConfig {
+                full_every_updates: 0,
+                full_every_minutes: 0,
+                full_above_changed_percent: 0,
+            },
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        for msg in [
+            DisplayMessage::Message("Hello".to_string()),
+            DisplayMessage::Update,
+            DisplayMessage::FullRefresh,
+            DisplayMessage::Update,
+        ] {
+            tx.send(msg).unwrap();
+        }
+        drop(tx);
+
+        let mut fake = Fake {
+            refreshes: Vec::new(),
+        };
+        run(&mut fake, config, rx).unwrap();
+        assert_eq!(
+            fake.refreshes,
+            [Refresh::Partial, Refresh::Full, Refresh::Partial]
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use anyhow::Result;
+
+use embedded_graphics::prelude::*;
+#[cfg(feature = "epd2in9bc")]
+use embedded_graphics::primitives::Rectangle;
+
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::{delay, gpio, spi};
+
+use epd_waveshare::color::Color;
+use epd_waveshare::prelude::*;
+
+use std;
+
+use self::panel::{Buffer, Epd, ROTATION};
+#[cfg(not(feature = "epd2in9bc"))]
+use self::panel::{PARTIAL_WINDOW, WIDTH};
+use super::backend::{ColorDepth, DisplayBackend, Refresh};
+#[cfg(not(feature = "epd2in9bc"))]
+use super::window::changed_windows;
+use super::Palette;
+
+#[cfg(not(any(
+    feature = "epd3in7",
+    feature = "epd2in9",
+    feature = "epd2in9bc",
+    feature = "epd4in2",
+    feature = "epd7in5"
+)))]
+compile_error!(
+    "Pick an e-paper panel feature (epd3in7, epd2in9, epd2in9bc, epd4in2 or epd7in5) or ttgo"
+);
+
+#[cfg(any(
+    all(
+        feature = "epd3in7",
+        any(
+            feature = "epd2in9",
+            feature = "epd2in9bc",
+            feature = "epd4in2",
+            feature = "epd7in5"
+        )
+    ),
+    all(
+        feature = "epd2in9",
+        any(feature = "epd2in9bc", feature = "epd4in2", feature = "epd7in5")
+    ),
+    all(feature = "epd2in9bc", any(feature = "epd4in2", feature = "epd7in5")),
+    all(feature = "epd4in2", feature = "epd7in5"),
+))]
+compile_error!(
+    "Only one e-paper panel feature can be enabled, epd3in7 is a default one: \
+     pick another with --no-default-features"
+);
+
+/// The Waveshare panel picked with the cargo features. Except for the three
+/// color one, `PARTIAL_WINDOW` tells whether its driver can update a window
+/// of the frame.
+#[cfg(feature = "epd3in7")]
+mod panel {
+    pub use epd_waveshare::epd3in7::{Display3in7 as Buffer, EPD3in7 as Epd, WIDTH};
+    use epd_waveshare::prelude::DisplayRotation;
+    pub const ROTATION: DisplayRotation = DisplayRotation::Rotate90;
+    // `update_partial_frame` is still a `todo!()` in the driver, quick
+    // refreshes push the whole frame until it gets one
+    pub const PARTIAL_WINDOW: bool = false;
+}
+
+#[cfg(feature = "epd2in9")]
+mod panel {
+    pub use epd_waveshare::epd2in9::{Display2in9 as Buffer, Epd2in9 as Epd, WIDTH};
+    use epd_waveshare::prelude::DisplayRotation;
+    pub const ROTATION: DisplayRotation = DisplayRotation::Rotate90;
+    pub const PARTIAL_WINDOW: bool = true;
+}
+
+#[cfg(feature = "epd2in9bc")]
+mod panel {
+    pub use epd_waveshare::epd2in9bc::{Display2in9bc as Buffer, Epd2in9bc as Epd};
+    use epd_waveshare::prelude::DisplayRotation;
+    pub const ROTATION: DisplayRotation = DisplayRotation::Rotate90;
+}
+
+#[cfg(feature = "epd4in2")]
+mod panel {
+    pub use epd_waveshare::epd4in2::{Display4in2 as Buffer, Epd4in2 as Epd, WIDTH};
+    use epd_waveshare::prelude::DisplayRotation;
+    pub const ROTATION: DisplayRotation = DisplayRotation::Rotate0;
+    pub const PARTIAL_WINDOW: bool = true;
+}
+
+#[cfg(feature = "epd7in5")]
+mod panel {
+    pub use epd_waveshare::epd7in5_v2::{Display7in5 as Buffer, Epd7in5 as Epd, WIDTH};
+    use epd_waveshare::prelude::DisplayRotation;
+    pub const ROTATION: DisplayRotation = DisplayRotation::Rotate0;
+    pub const PARTIAL_WINDOW: bool = false;
+}
+
+#[cfg(not(feature = "epd2in9bc"))]
+const PALETTE: Palette<Color> = Palette {
+    background: Color::White,
+    text: Color::Black,
+    header: Color::Black,
+    eta: Color::Black,
+    missed: Color::Black,
+};
+
+/// Bytes per row of the frame buffers, in the panel's native orientation
+#[cfg(not(feature = "epd2in9bc"))]
+const ROW_BYTES: usize = (WIDTH as usize + 7) / 8;
+
+type Spi = spi::Master<
+    spi::SPI2,
+    gpio::Gpio18<gpio::Unknown>,
+    gpio::Gpio19<gpio::Unknown>,
+    gpio::Gpio21<gpio::Unknown>,
+    gpio::Gpio5<gpio::Unknown>,
+>;
+
+type Driver = Epd<
+    Spi,
+    gpio::Gpio5<gpio::Output>,
+    gpio::Gpio4<gpio::Input>,
+    gpio::Gpio16<gpio::Output>,
+    gpio::Gpio13<gpio::Output>,
+    delay::FreeRtos,
+>;
+
+/// Waveshare e-paper panel, drawn in landscape
+pub struct Eink {
+    spi: Spi,
+    epd: Driver,
+    #[cfg(not(feature = "epd2in9bc"))]
+    display: Box<Buffer>,
+    #[cfg(feature = "epd2in9bc")]
+    display: Box<TriColorBuffer>,
+    /// Copy of the frame buffers on the panel
+    shown: Vec<u8>,
+    lut: RefreshLut,
+}
+
+impl Eink {
+    pub fn new(
+        busy: gpio::Gpio4<gpio::Unknown>,
+        dc: gpio::Gpio16<gpio::Unknown>,
+        rst: gpio::Gpio13<gpio::Unknown>,
+        spi: spi::SPI2,
+        sclk: gpio::Gpio18<gpio::Unknown>,
+        sdo: gpio::Gpio19<gpio::Unknown>,
+        cs: gpio::Gpio5<gpio::Unknown>,
+    ) -> Result<Self> {
+        let spi_config = <spi::config::Config as Default>::default().baudrate(26.MHz().into());
+
+        println!("Setup eink display SPI interface");
+
+        let mut spi_interface = spi::Master::<spi::SPI2, _, _, _, _>::new(
+            spi,
+            spi::Pins {
+                sclk,
+                sdo,
+                sdi: Option::<gpio::Gpio21<gpio::Unknown>>::None,
+                cs: Option::<gpio::Gpio5<gpio::Unknown>>::None,
+            },
+            spi_config,
+        )?;
+
+        let epd = Epd::new(
+            &mut spi_interface,
+            cs.into_output()?,
+            busy.into_input()?,
+            dc.into_output()?,
+            rst.into_output()?,
+            &mut delay::FreeRtos,
+        )?;
+
+        Ok(Eink {
+            spi: spi_interface,
+            epd,
+            display: Box::new(Default::default()),
+            shown: Vec::new(),
+            lut: RefreshLut::Full,
+        })
+    }
+
+    fn set_lut(&mut self, lut: RefreshLut) -> Result<()> {
+        if self.lut != lut {
+            self.epd.set_lut(&mut self.spi, Some(lut))?;
+            self.lut = lut;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "epd2in9bc"))]
+    fn buffers(&self) -> [&[u8]; 1] {
+        [self.display.buffer()]
+    }
+
+    #[cfg(feature = "epd2in9bc")]
+    fn buffers(&self) -> [&[u8]; 2] {
+        [self.display.mono.buffer(), self.display.chromatic.buffer()]
+    }
+
+    #[cfg(not(feature = "epd2in9bc"))]
+    fn show(&mut self) -> Result<()> {
+        self.epd.update_and_display_frame(
+            &mut self.spi,
+            self.display.buffer(),
+            &mut delay::FreeRtos,
+        )?;
+        self.shown = self.buffers().concat();
+        Ok(())
+    }
+
+    /// Pushes only the windows of the frame that changed since the last
+    /// refresh, falling back to the whole frame when the panel can't
+    #[cfg(not(feature = "epd2in9bc"))]
+    fn show_changed(&mut self) -> Result<()> {
+        if !PARTIAL_WINDOW || self.shown.is_empty() {
+            return self.show();
+        }
+
+        let windows = changed_windows(&self.shown, self.display.buffer(), ROW_BYTES);
+        if windows.is_empty() {
+            return Ok(());
+        }
+
+        for window in &windows {
+            let data = window.extract(self.display.buffer(), ROW_BYTES);
+            self.epd.update_partial_frame(
+                &mut self.spi,
+                &data,
+                window.x,
+                window.y,
+                window.width,
+                window.height,
+            )?;
+        }
+        self.epd
+            .display_frame(&mut self.spi, &mut delay::FreeRtos)?;
+
+        self.shown = self.buffers().concat();
+        Ok(())
+    }
+
+    #[cfg(feature = "epd2in9bc")]
+    fn show_changed(&mut self) -> Result<()> {
+        self.show()
+    }
+
+    #[cfg(feature = "epd2in9bc")]
+    fn show(&mut self) -> Result<()> {
+        self.epd.update_color_frame(
+            &mut self.spi,
+            self.display.mono.buffer(),
+            self.display.chromatic.buffer(),
+        )?;
+        self.epd
+            .display_frame(&mut self.spi, &mut delay::FreeRtos)?;
+        self.shown = self.buffers().concat();
+        Ok(())
+    }
+}
+
+impl DisplayBackend for Eink {
+    #[cfg(not(feature = "epd2in9bc"))]
+    type Color = Color;
+    #[cfg(not(feature = "epd2in9bc"))]
+    type Target = Buffer;
+
+    #[cfg(feature = "epd2in9bc")]
+    type Color = TriColor;
+    #[cfg(feature = "epd2in9bc")]
+    type Target = TriColorBuffer;
+
+    fn init(&mut self) -> Result<()> {
+        // Cycle the panel through black and white to clear any ghosting
+        let palette = self.palette();
+        self.set_lut(RefreshLut::Quick)?;
+        self.display.clear(palette.text).unwrap();
+        self.show()?;
+        self.display.clear(palette.background).unwrap();
+        self.show()?;
+        self.display.set_rotation(ROTATION);
+        Ok(())
+    }
+
+    fn target(&mut self) -> &mut Self::Target {
+        &mut self.display
+    }
+
+    fn refresh(&mut self, refresh: Refresh) -> Result<()> {
+        match refresh {
+            Refresh::Full => {
+                self.set_lut(RefreshLut::Full)?;
+                self.show()
+            }
+            Refresh::Partial => {
+                self.set_lut(RefreshLut::Quick)?;
+                self.show_changed()
+            }
+        }
+    }
+
+    fn changed(&self) -> Option<f32> {
+        // Counted in bytes, 8 pixels each, close enough to tell small updates
+        // from whole new screens
+        let buffers = self.buffers();
+        let mut total = 0;
+        let mut changed = 0;
+        for (new, old) in buffers.iter().flat_map(|b| b.iter()).zip(&self.shown) {
+            total += 1;
+            if new != old {
+                changed += 1;
+            }
+        }
+
+        if total == 0 {
+            return None;
+        }
+        Some(changed as f32 / total as f32)
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        // The last frame stays on the panel
+        self.epd.sleep(&mut self.spi, &mut delay::FreeRtos)?;
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        self.display.bounding_box().size
+    }
+
+    #[cfg(not(feature = "epd2in9bc"))]
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::Mono
+    }
+
+    #[cfg(feature = "epd2in9bc")]
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::TriColor
+    }
+
+    #[cfg(not(feature = "epd2in9bc"))]
+    fn palette(&self) -> Palette<Color> {
+        PALETTE
+    }
+
+    #[cfg(feature = "epd2in9bc")]
+    fn palette(&self) -> Palette<TriColor> {
+        TRI_COLOR_PALETTE
+    }
+}
+
+/// Colors of the three color panels, the accent is red or yellow depending on
+/// the model
+#[cfg(feature = "epd2in9bc")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriColor {
+    White,
+    Black,
+    Accent,
+}
+
+#[cfg(feature = "epd2in9bc")]
+impl PixelColor for TriColor {
+    type Raw = ();
+}
+
+#[cfg(feature = "epd2in9bc")]
+const TRI_COLOR_PALETTE: Palette<TriColor> = Palette {
+    background: TriColor::White,
+    text: TriColor::Black,
+    header: TriColor::Accent,
+    eta: TriColor::Black,
+    missed: TriColor::Accent,
+};
+
+/// Three color panels take two frames, the black pixels and the ones shown in
+/// the accent color (drawn black)
+#[cfg(feature = "epd2in9bc")]
+#[derive(Default)]
+pub struct TriColorBuffer {
+    mono: Buffer,
+    chromatic: Buffer,
+}
+
+#[cfg(feature = "epd2in9bc")]
+impl TriColorBuffer {
+    fn set_rotation(&mut self, rotation: DisplayRotation) {
+        self.mono.set_rotation(rotation);
+        self.chromatic.set_rotation(rotation);
+    }
+}
+
+#[cfg(feature = "epd2in9bc")]
+impl Dimensions for TriColorBuffer {
+    fn bounding_box(&self) -> Rectangle {
+        self.mono.bounding_box()
+    }
+}
+
+#[cfg(feature = "epd2in9bc")]
+impl DrawTarget for TriColorBuffer {
+    type Color = TriColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<TriColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (mono, chromatic) = match color {
+                TriColor::White => (Color::White, Color::White),
+                TriColor::Black => (Color::Black, Color::White),
+                TriColor::Accent => (Color::White, Color::Black),
+            };
+            Pixel(point, mono).draw(&mut self.mono)?;
+            Pixel(point, chromatic).draw(&mut self.chromatic)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use super::backend::Refresh;
+use crate::config::RefreshConfig;
+
+/// Picks a full refresh now and then to clear the ghosting quick refreshes
+/// leave on e-ink panels.
+pub struct RefreshPolicy {
+    config: RefreshConfig,
+    quick_updates: u32,
+    last_full: Option<Instant>,
+    full_requested: bool,
+}
+
+impl RefreshPolicy {
+    pub fn new(config: RefreshConfig) -> Self {
+        RefreshPolicy {
+            config,
+            quick_updates: 0,
+            last_full: None,
+            full_requested: false,
+        }
+    }
+
+    /// Makes the next refresh a full one
+    pub fn request_full(&mut self) {
+        self.full_requested = true;
+    }
+
+    /// Picks how to show the next frame, `changed` is the fraction of the
+    /// screen that changed since the last one, when the backend can tell.
+    pub fn next(&mut self, changed: Option<f32>) -> Refresh {
+        self.next_at(changed, Instant::now())
+    }
+
+    fn next_at(&mut self, changed: Option<f32>, now: Instant) -> Refresh {
+        let config = &self.config;
+
+        let too_many =
+            config.full_every_updates != 0 && self.quick_updates >= config.full_every_updates;
+        let too_old = config.full_every_minutes != 0
+            && self.last_full.map_or(true, |last| {
+                now.duration_since(last)
+                    >= Duration::from_secs(config.full_every_minutes as u64 * 60)
+            });
+        let large_change = config.full_above_changed_percent != 0
+            && changed.map_or(false, |c| {
+                c * 100.0 >= config.full_above_changed_percent as f32
+            });
+
+        if self.full_requested || too_many || too_old || large_change {
+            self.full_requested = false;
+            self.quick_updates = 0;
+            self.last_full = Some(now);
+            Refresh::Full
+        } else {
+            self.quick_updates += 1;
+            Refresh::Partial
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(full_every_updates: u32, full_every_minutes: u32, percent: u32) -> RefreshPolicy {
+        RefreshPolicy::new(RefreshConfig {
+            full_every_updates,
+            full_every_minutes,
+            full_above_changed_percent: percent,
+        })
+    }
+
+    #[test]
+    fn full_refresh_every_n_updates() {
+        let mut policy = policy(3, 0, 0);
+        for _ in 0..2 {
+            for _ in 0..3 {
+                assert_eq!(policy.next(None), Refresh::Partial);
+            }
+            assert_eq!(policy.next(None), Refresh::Full);
+        }
+    }
+
+    #[test]
+    fn full_refresh_every_m_minutes() {
+        let mut policy = policy(0, 15, 0);
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+
+        // Nothing is known of what is on the panel yet
+        assert_eq!(policy.next_at(None, minutes(0)), Refresh::Full);
+        assert_eq!(policy.next_at(None, minutes(14)), Refresh::Partial);
+        assert_eq!(policy.next_at(None, minutes(15)), Refresh::Full);
+        assert_eq!(policy.next_at(None, minutes(29)), Refresh::Partial);
+        assert_eq!(policy.next_at(None, minutes(30)), Refresh::Full);
+    }
+
+    #[test]
+    fn full_refresh_on_large_changes() {
+        let mut policy = policy(0, 0, 40);
+        assert_eq!(policy.next(Some(0.39)), Refresh::Partial);
+        assert_eq!(policy.next(Some(0.4)), Refresh::Full);
+        assert_eq!(policy.next(Some(0.1)), Refresh::Partial);
+        // Backends that can't tell what changed
+        assert_eq!(policy.next(None), Refresh::Partial);
+    }
+
+    #[test]
+    fn full_refresh_on_request() {
+        let mut policy = policy(0, 0, 0);
+        assert_eq!(policy.next(Some(1.0)), Refresh::Partial);
+        policy.request_full();
+        assert_eq!(policy.next(None), Refresh::Full);
+        assert_eq!(policy.next(None), Refresh::Partial);
+    }
+
+    #[test]
+    fn full_refresh_restarts_the_counters() {
+        let mut policy = policy(3, 15, 0);
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+
+        assert_eq!(policy.next_at(None, minutes(0)), Refresh::Full);
+        assert_eq!(policy.next_at(None, minutes(1)), Refresh::Partial);
+        assert_eq!(policy.next_at(None, minutes(2)), Refresh::Partial);
+        policy.request_full();
+        assert_eq!(policy.next_at(None, minutes(10)), Refresh::Full);
+        // Three more quick refreshes, and 15 minutes from the requested one
+        for m in 11..14 {
+            assert_eq!(policy.next_at(None, minutes(m)), Refresh::Partial);
+        }
+        assert_eq!(policy.next_at(None, minutes(14)), Refresh::Full);
+        assert_eq!(policy.next_at(None, minutes(15)), Refresh::Partial);
+        assert_eq!(policy.next_at(None, minutes(24)), Refresh::Partial);
+        assert_eq!(policy.next_at(None, minutes(29)), Refresh::Full);
+    }
+}
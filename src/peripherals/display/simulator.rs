@@ -0,0 +1,155 @@
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::Result;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics_simulator::{
+    BinaryColorTheme, OutputSettings, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent,
+    Window,
+};
+
+use super::backend::{self, ColorDepth, DisplayBackend, Refresh};
+use super::{DisplayMessage, Palette, WifiStatus, HEIGHT, MONO_PALETTE, WIDTH};
+use crate::config::Config;
+use crate::emtmadrid::ArrivalTime;
+use crate::peripherals::battery::BatteryStatus;
+
+// How often the window is checked for events while waiting for messages
+const EVENT_POLL: Duration = Duration::from_millis(50);
+
+pub enum Output {
+    /// Show every frame in a desktop window (needs SDL2)
+    Window,
+    /// Write every frame as a numbered PNG file into the directory
+    Png(PathBuf),
+}
+
+/// Stand-in for the e-ink panel, shows the frames in a window or PNG files
+pub struct Simulator {
+    display: SimulatorDisplay<BinaryColor>,
+    output: Output,
+    window: Option<Window>,
+    frame: usize,
+}
+
+impl Simulator {
+    pub fn new(output: Output) -> Self {
+        Simulator {
+            display: SimulatorDisplay::new(Size::new(WIDTH, HEIGHT)),
+            output,
+            window: None,
+            frame: 0,
+        }
+    }
+}
+
+impl DisplayBackend for Simulator {
+    type Color = BinaryColor;
+    type Target = SimulatorDisplay<BinaryColor>;
+
+    const IDLE_INTERVAL: Option<Duration> = Some(EVENT_POLL);
+
+    fn init(&mut self) -> Result<()> {
+        match &self.output {
+            Output::Window => {
+                let mut window = Window::new("Bus monitor", &output_settings(2));
+                window.update(&self.display);
+                self.window = Some(window);
+            }
+            Output::Png(dir) => std::fs::create_dir_all(dir)?,
+        }
+        Ok(())
+    }
+
+    fn target(&mut self) -> &mut SimulatorDisplay<BinaryColor> {
+        &mut self.display
+    }
+
+    fn refresh(&mut self, _refresh: Refresh) -> Result<()> {
+        match (&self.output, &mut self.window) {
+            (_, Some(window)) => window.update(&self.display),
+            (Output::Png(dir), None) => {
+                let path = dir.join(format!("frame{:04}.png", self.frame));
+                self.display
+                    .to_rgb_output_image(&output_settings(1))
+                    .save_png(&path)?;
+                println!("Wrote {}", path.display());
+            }
+            _ => {}
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        // Keep showing the last frame once there is nothing more to draw
+        if let Some(window) = &mut self.window {
+            window.show_static(&self.display);
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        self.display.size()
+    }
+
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::Mono
+    }
+
+    fn palette(&self) -> Palette<BinaryColor> {
+        MONO_PALETTE
+    }
+
+    fn idle(&mut self) -> bool {
+        match &mut self.window {
+            Some(window) => !window.events().any(|e| matches!(e, SimulatorEvent::Quit)),
+            None => true,
+        }
+    }
+}
+
+/// Renders the messages received on `rx` like the e-ink panel would, until
+/// the channel is closed (and the window, if any, is closed by the user).
+pub fn run(config: Config, rx: mpsc::Receiver<DisplayMessage>, output: Output) -> Result<()> {
+    backend::run(Simulator::new(output), config, rx)
+}
+
+fn output_settings(scale: u32) -> OutputSettings {
+    OutputSettingsBuilder::new()
+        .theme(BinaryColorTheme::LcdWhite)
+        .scale(scale)
+        .build()
+}
+
+/// Canned battery and Wi-Fi state shown by the simulator
+pub const SAMPLE_BATTERY: BatteryStatus = BatteryStatus {
+    percent: 60.0,
+    charging: false,
+};
+pub const SAMPLE_WIFI: WifiStatus = WifiStatus::Connected(-62.0);
+
+/// A canned set of arrivals covering the different kinds of rows, for when
+/// there is no EMT endpoint to ask.
+pub fn sample_arrivals() -> Vec<ArrivalTime> {
+    let arrival = |time, stop: &str, line: &str, destination: &str| ArrivalTime {
+        time,
+        stop: stop.to_string(),
+        line: line.to_string(),
+        destination: destination.to_string(),
+    };
+
+    let mut arrivals = vec![
+        arrival(0, "874", "31", "Plaza Mayor"),
+        arrival(150, "1455", "65", "Plaza Jacinto Benavente"),
+        arrival(260, "874", "138", "Plaza de Espana"),
+        arrival(420, "1455", "33", "Principe Pio"),
+        arrival(610, "874", "39", "Plaza de Espana"),
+        arrival(900, "1455", "36", "Atocha"),
+        arrival(20000, "874", "N18", "Cibeles"),
+    ];
+    arrivals.sort();
+    arrivals
+}
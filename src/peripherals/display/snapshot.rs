@@ -0,0 +1,293 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use time::OffsetDateTime;
+
+use super::{DisplayMessage, Renderer, WifiStatus, HEIGHT, MONO_PALETTE, WIDTH};
+use crate::config::Config;
+use crate::emtmadrid::{ArrivalTime, Arrivals};
+use crate::peripherals::battery::BatteryStatus;
+
+/// Set to rewrite the reference images instead of comparing with them
+const BLESS_VAR: &str = "BLESS_SCREENS";
+
+/// 2023-01-16 08:15 in Madrid, every screen is rendered at this time
+const FIXED_TIMESTAMP: i64 = 1673853300;
+
+/// Panel sizes every scenario is rendered at, with the prefix of their
+/// reference images: the default 3.7", a compact 2.9" and a large 7.5" one
+const PANELS: [(&str, u32, u32); 3] = [
+    ("", WIDTH, HEIGHT),
+    ("2in9_", 296, 128),
+    ("7in5_", 800, 480),
+];
+
+/// A 1-bit frame buffer the screens are rendered into for comparison
+#[derive(PartialEq)]
+pub struct Frame {
+    size: Size,
+    pixels: Vec<bool>,
+}
+
+impl Frame {
+    pub fn new(size: Size) -> Self {
+        Frame {
+            size,
+            pixels: vec![false; (size.width * size.height) as usize],
+        }
+    }
+
+    fn index(&self, point: Point) -> Option<usize> {
+        let (x, y) = (point.x as u32, point.y as u32);
+        if point.x < 0 || point.y < 0 || x >= self.size.width || y >= self.size.height {
+            return None;
+        }
+        Some((y * self.size.width + x) as usize)
+    }
+
+    /// Encodes the frame as a binary PBM (P4) image, set pixels are black
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut data = format!("P4\n{} {}\n", self.size.width, self.size.height).into_bytes();
+        for row in self.pixels.chunks(self.size.width as usize) {
+            for byte in row.chunks(8) {
+                let bits = byte
+                    .iter()
+                    .enumerate()
+                    .fold(0_u8, |acc, (bit, on)| acc | ((*on as u8) << (7 - bit)));
+                data.push(bits);
+            }
+        }
+        data
+    }
+
+    /// Decodes a binary PBM (P4) image as written by `to_pbm`
+    pub fn from_pbm(data: &[u8]) -> Result<Frame> {
+        // Magic, width and height, each followed by a single whitespace
+        let mut fields = Vec::new();
+        let mut start = 0;
+        for (pos, byte) in data.iter().enumerate() {
+            if byte.is_ascii_whitespace() {
+                if pos > start {
+                    fields.push(std::str::from_utf8(&data[start..pos])?);
+                }
+                start = pos + 1;
+                if fields.len() == 3 {
+                    break;
+                }
+            }
+        }
+
+        match fields.as_slice() {
+            ["P4", width, height] => {
+                let size = Size::new(width.parse()?, height.parse()?);
+                let row_bytes = ((size.width + 7) / 8) as usize;
+                let bits = &data[start..];
+                if bits.len() < row_bytes * size.height as usize {
+                    bail!("Truncated PBM image");
+                }
+
+                let mut frame = Frame::new(size);
+                for y in 0..size.height as usize {
+                    for x in 0..size.width as usize {
+                        let byte = bits[y * row_bytes + x / 8];
+                        frame.pixels[y * size.width as usize + x] = byte & (0x80 >> (x % 8)) != 0;
+                    }
+                }
+                Ok(frame)
+            }
+            _ => Err(anyhow!("Not a binary PBM image")),
+        }
+    }
+
+    /// Encodes a PPM (P6) image of `self` with the pixels that differ from
+    /// `reference` in red, the rest of the frame is dimmed to make them stand out
+    pub fn diff_ppm(&self, reference: &Frame) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.size.width, self.size.height).into_bytes();
+        for (idx, on) in self.pixels.iter().enumerate() {
+            let rgb: [u8; 3] = match reference.pixels.get(idx) {
+                Some(expected) if expected != on => [255, 0, 0],
+                _ if *on => [96, 96, 96],
+                _ => [255, 255, 255],
+            };
+            data.extend_from_slice(&rgb);
+        }
+        data
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Some(idx) = self.index(point) {
+                self.pixels[idx] = color.is_on();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fixed_time() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(FIXED_TIMESTAMP).unwrap()
+}
+
+fn arrival(time: u64, stop: &str, line: &str, destination: &str) -> ArrivalTime {
+    ArrivalTime {
+        time,
+        stop: stop.to_string(),
+        line: line.to_string(),
+        destination: destination.to_string(),
+    }
+}
+
+/// The arrivals screens covered by the reference images
+pub fn scenarios() -> Vec<(&'static str, Vec<ArrivalTime>)> {
+    vec![
+        ("empty", vec![]),
+        ("one_bus", vec![arrival(420, "874", "31", "Plaza Mayor")]),
+        (
+            "overflow",
+            (0..16)
+                .map(|n| arrival(60 + n * 90, "874", "65", "Plaza Jacinto Benavente"))
+                .collect(),
+        ),
+        (
+            "arriving_now",
+            vec![
+                arrival(0, "1455", "36", "Atocha"),
+                arrival(300, "874", "138", "Plaza de Espana"),
+            ],
+        ),
+        (
+            "no_estimate",
+            vec![
+                arrival(240, "874", "39", "Plaza de Espana"),
+                arrival(999999, "1455", "33", "Principe Pio"),
+            ],
+        ),
+        (
+            "unknown_line",
+            vec![
+                arrival(180, "874", "N18", "Cibeles"),
+                arrival(600, "9999", "31", "Plaza Mayor"),
+            ],
+        ),
+    ]
+}
+
+/// Renders an arrivals screen at the fixed time, with a 3/4 full battery and a
+/// fair Wi-Fi signal
+pub fn render(config: &Config, size: Size, arrivals: Vec<ArrivalTime>) -> Frame {
+    let mut frame = Frame::new(size);
+    let mut renderer = Renderer::with_clock(config.clone(), MONO_PALETTE, size, fixed_time);
+
+    renderer
+        .draw(
+            &mut frame,
+            DisplayMessage::Battery(BatteryStatus {
+                percent: 75.0,
+                charging: false,
+            }),
+        )
+        .unwrap();
+    renderer
+        .draw(
+            &mut frame,
+            DisplayMessage::WiFi(WifiStatus::Connected(-70.0)),
+        )
+        .unwrap();
+    renderer.draw(&mut frame, DisplayMessage::Clear).unwrap();
+    renderer
+        .draw(
+            &mut frame,
+            DisplayMessage::Arrivals(Arrivals::new(arrivals, fixed_time())),
+        )
+        .unwrap();
+    frame
+}
+
+/// Compares every scenario against its reference image in `dir`, writing a
+/// `<name>.diff.ppm` next to the ones that don't match. With `bless` the
+/// reference images are (re)written instead.
+pub fn check(dir: &Path, bless: bool) -> Result<()> {
+    let config = Config::default();
+    let mut screens = Vec::new();
+    let mut failed = Vec::new();
+
+    if bless {
+        fs::create_dir_all(dir)?;
+    }
+
+    for (prefix, width, height) in PANELS {
+        for (scenario, arrivals) in scenarios() {
+            let name = format!("{}{}", prefix, scenario);
+            let frame = render(&config, Size::new(width, height), arrivals);
+            screens.push((name, frame));
+        }
+    }
+
+    for (name, frame) in &screens {
+        let reference_path = dir.join(format!("{}.pbm", name));
+        let diff_path = dir.join(format!("{}.diff.ppm", name));
+
+        if bless {
+            fs::write(&reference_path, frame.to_pbm())?;
+            let _ = fs::remove_file(&diff_path);
+            println!("Wrote {}", reference_path.display());
+            continue;
+        }
+
+        let reference = match fs::read(&reference_path) {
+            Ok(data) => Frame::from_pbm(&data)?,
+            Err(e) => {
+                eprintln!("{}: can't read {}: {}", name, reference_path.display(), e);
+                failed.push(name.as_str());
+                continue;
+            }
+        };
+
+        if *frame == reference {
+            println!("{}: ok", name);
+            let _ = fs::remove_file(&diff_path);
+        } else {
+            fs::write(&diff_path, frame.diff_ppm(&reference))?;
+            eprintln!("{}: differs, see {}", name, diff_path.display());
+            failed.push(name.as_str());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} screens don't match: {}",
+            failed.len(),
+            screens.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn screens_match_the_references() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("screens");
+    if let Err(e) = check(&dir, std::env::var_os(BLESS_VAR).is_some()) {
+        panic!(
+            "{}, set {}=1 to update the references if intended",
+            e, BLESS_VAR
+        );
+    }
+}
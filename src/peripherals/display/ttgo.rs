@@ -0,0 +1,163 @@
+use anyhow::Result;
+
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{ContainsPoint, Rectangle};
+
+use display_interface_spi::SPIInterfaceNoCS;
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::{delay, gpio, spi};
+
+use st7789;
+
+use std;
+use std::thread;
+use std::time::Duration;
+
+use super::backend::{ColorDepth, DisplayBackend, Refresh};
+use super::Palette;
+
+// The 135x240 panel of the TTGO T-Display sits at this offset of the ST7789
+// 240x320 frame memory in landscape
+const TOP_LEFT: Point = Point::new(40, 53);
+const SIZE: Size = Size::new(240, 135);
+
+const PALETTE: Palette<Rgb565> = Palette {
+    background: Rgb565::BLACK,
+    text: Rgb565::WHITE,
+    header: Rgb565::CYAN,
+    eta: Rgb565::YELLOW,
+    missed: Rgb565::RED,
+};
+
+type Interface = SPIInterfaceNoCS<
+    spi::Master<
+        spi::SPI2,
+        gpio::Gpio18<gpio::Unknown>,
+        gpio::Gpio19<gpio::Unknown>,
+        gpio::Gpio21<gpio::Unknown>,
+        gpio::Gpio5<gpio::Unknown>,
+    >,
+    gpio::Gpio16<gpio::Output>,
+>;
+
+type Driver = st7789::ST7789<Interface, gpio::Gpio23<gpio::Output>>;
+
+/// ST7789 color LCD of the TTGO T-Display, drawn straight into the panel
+/// memory as there is no room for a frame buffer
+pub struct TDisplay {
+    driver: Driver,
+    backlight: gpio::Gpio4<gpio::Output>,
+}
+
+impl TDisplay {
+    pub fn new(
+        backlight: gpio::Gpio4<gpio::Unknown>,
+        dc: gpio::Gpio16<gpio::Unknown>,
+        rst: gpio::Gpio23<gpio::Unknown>, // TTGO ESP32
+        spi: spi::SPI2,
+        sclk: gpio::Gpio18<gpio::Unknown>,
+        sdo: gpio::Gpio19<gpio::Unknown>,
+        cs: gpio::Gpio5<gpio::Unknown>,
+    ) -> Result<Self> {
+        let spi_config = <spi::config::Config as Default>::default().baudrate(26.MHz().into());
+
+        let di = SPIInterfaceNoCS::new(
+            spi::Master::<spi::SPI2, _, _, _, _>::new(
+                spi,
+                spi::Pins {
+                    sclk,
+                    sdo,
+                    sdi: Option::<gpio::Gpio21<gpio::Unknown>>::None,
+                    cs: Some(cs),
+                },
+                spi_config,
+            )?,
+            dc.into_output()?,
+        );
+
+        Ok(TDisplay {
+            driver: st7789::ST7789::new(di, rst.into_output()?, 240, 320),
+            backlight: backlight.into_output()?,
+        })
+    }
+}
+
+impl Dimensions for TDisplay {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), SIZE)
+    }
+}
+
+impl DrawTarget for TDisplay {
+    type Color = Rgb565;
+    type Error = <Driver as DrawTarget>::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let area = self.bounding_box();
+        self.driver.draw_iter(
+            pixels
+                .into_iter()
+                .filter(|Pixel(point, _)| area.contains(*point))
+                .map(|Pixel(point, color)| Pixel(point + TOP_LEFT, color)),
+        )
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        self.driver.fill_solid(&area.translate(TOP_LEFT), color)
+    }
+}
+
+impl DisplayBackend for TDisplay {
+    type Color = Rgb565;
+    type Target = TDisplay;
+
+    fn init(&mut self) -> Result<()> {
+        self.driver
+            .init(&mut delay::Ets)
+            .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+        self.driver
+            .set_orientation(st7789::Orientation::Landscape)
+            .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+
+        self.driver.clear(Rgb565::BLACK).unwrap();
+        self.backlight.set_high()?;
+        Ok(())
+    }
+
+    fn target(&mut self) -> &mut TDisplay {
+        self
+    }
+
+    fn refresh(&mut self, _refresh: Refresh) -> Result<()> {
+        // Drawing already went to the panel
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        self.driver.clear(Rgb565::BLUE).unwrap();
+        thread::sleep(Duration::from_millis(1000));
+
+        self.driver
+            .hard_reset(&mut delay::Ets)
+            .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+        self.backlight.set_low()?;
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        SIZE
+    }
+
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::Rgb565
+    }
+
+    fn palette(&self) -> Palette<Rgb565> {
+        PALETTE
+    }
+}
@@ -0,0 +1,182 @@
+/// Changed rows closer than this are pushed in a single window
+const MERGE_ROWS: usize = 8;
+
+/// Area of a 1 bit frame buffer, in pixels of the panel's native orientation,
+/// `x` and `width` are whole bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Window {
+    /// Copies the bytes of the window out of a frame buffer with rows of
+    /// `row_bytes`
+    pub fn extract(&self, buffer: &[u8], row_bytes: usize) -> Vec<u8> {
+        let (first, bytes) = (self.x as usize / 8, self.width as usize / 8);
+        buffer
+            .chunks(row_bytes)
+            .skip(self.y as usize)
+            .take(self.height as usize)
+            .flat_map(|row| &row[first..first + bytes])
+            .copied()
+            .collect()
+    }
+}
+
+/// Windows covering the bytes that differ between two frame buffers with rows
+/// of `row_bytes`: bands of changed rows (the clock, the arrivals that
+/// changed, the bus timeline) each narrowed to the columns that changed in
+/// them.
+pub fn changed_windows(old: &[u8], new: &[u8], row_bytes: usize) -> Vec<Window> {
+    let mut windows: Vec<Window> = Vec::new();
+    // Rows and byte columns of the band being built
+    let mut band: Option<(usize, usize, usize, usize)> = None;
+
+    let rows = old.chunks(row_bytes).zip(new.chunks(row_bytes)).enumerate();
+    for (y, (old_row, new_row)) in rows {
+        let mut columns = old_row
+            .iter()
+            .zip(new_row)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(x, _)| x);
+        let first = match columns.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let last = columns.last().unwrap_or(first);
+
+        band = match band {
+            Some((top, bottom, left, right)) if y - bottom <= MERGE_ROWS => {
+                Some((top, y, left.min(first), right.max(last)))
+            }
+            Some(done) => {
+                windows.push(band_window(done));
+                Some((y, y, first, last))
+            }
+            None => Some((y, y, first, last)),
+        };
+    }
+
+    if let Some(done) = band {
+        windows.push(band_window(done));
+    }
+    windows
+}
+
+fn band_window((top, bottom, left, right): (usize, usize, usize, usize)) -> Window {
+    Window {
+        x: left as u32 * 8,
+        y: top as u32,
+        width: (right - left + 1) as u32 * 8,
+        height: (bottom - top + 1) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 416x240 frame, the 3.7" panel in its native orientation
+    const ROW_BYTES: usize = 52;
+    const ROWS: usize = 240;
+
+    fn frame() -> Vec<u8> {
+        vec![0xff; ROW_BYTES * ROWS]
+    }
+
+    /// Sets the bytes of `columns` in `rows`, like drawing something there
+    fn draw(frame: &mut [u8], rows: std::ops::Range<usize>, columns: std::ops::Range<usize>) {
+        for row in rows {
+            for column in columns.clone() {
+                frame[row * ROW_BYTES + column] ^= 0x5a;
+            }
+        }
+    }
+
+    #[test]
+    fn identical_frames_have_no_windows() {
+        assert_eq!(changed_windows(&frame(), &frame(), ROW_BYTES), []);
+    }
+
+    #[test]
+    fn clock_is_a_small_window() {
+        let mut new = frame();
+        // A digit of the clock, right of the header
+        draw(&mut new, 2..22, 46..48);
+        let windows = changed_windows(&frame(), &new, ROW_BYTES);
+        assert_eq!(
+            windows,
+            [Window {
+                x: 46 * 8,
+                y: 2,
+                width: 16,
+                height: 20
+            }]
+        );
+        assert_eq!(windows[0].extract(&new, ROW_BYTES), vec![0xa5; 2 * 20]);
+    }
+
+    #[test]
+    fn changed_row_is_narrowed_to_its_columns() {
+        let mut new = frame();
+        // The minutes column of an arrivals row, with an edge of the countdown
+        draw(&mut new, 60..78, 30..34);
+        draw(&mut new, 70..71, 28..29);
+        assert_eq!(
+            changed_windows(&frame(), &new, ROW_BYTES),
+            [Window {
+                x: 28 * 8,
+                y: 60,
+                width: 6 * 8,
+                height: 18
+            }]
+        );
+    }
+
+    #[test]
+    fn timeline_strip_spans_the_width() {
+        let mut new = frame();
+        draw(&mut new, 224..240, 0..ROW_BYTES);
+        assert_eq!(
+            changed_windows(&frame(), &new, ROW_BYTES),
+            [Window {
+                x: 0,
+                y: 224,
+                width: 416,
+                height: 16
+            }]
+        );
+    }
+
+    #[test]
+    fn distant_changes_are_separate_windows() {
+        let mut new = frame();
+        draw(&mut new, 2..22, 46..48);
+        draw(&mut new, 60..78, 30..34);
+        draw(&mut new, 224..240, 0..ROW_BYTES);
+        let windows = changed_windows(&frame(), &new, ROW_BYTES);
+        let rows: Vec<(u32, u32)> = windows.iter().map(|w| (w.y, w.height)).collect();
+        assert_eq!(rows, [(2, 20), (60, 18), (224, 16)]);
+    }
+
+    #[test]
+    fn close_changes_share_a_window() {
+        let mut new = frame();
+        draw(&mut new, 60..78, 30..34);
+        // Two arrivals rows apart by less than `MERGE_ROWS`
+        draw(&mut new, 84..102, 10..12);
+        assert_eq!(
+            changed_windows(&frame(), &new, ROW_BYTES),
+            [Window {
+                x: 10 * 8,
+                y: 60,
+                width: 24 * 8,
+                height: 42
+            }]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use log::*;
+
+use crate::config::Config;
+use crate::emtmadrid::ArrivalTime;
+
+/// Seconds left before leaving home to catch the next bus that can still be
+/// caught walking to its stop. Stops without a configured walking time are
+/// left out, their arrivals keep coming so each is only logged the first
+/// time, `logged` keeps the ones that were.
+pub fn time_to_leave(
+    config: &Config,
+    arrivals: &[ArrivalTime],
+    logged: &mut BTreeSet<String>,
+) -> Option<u64> {
+    arrivals
+        .iter()
+        .filter_map(|arrival| {
+            let stop = config.stop(&arrival.stop);
+            if stop.is_none() && logged.insert(arrival.stop.clone()) {
+                warn!(
+                    "Stop {} is not configured, its arrivals are not followed",
+                    arrival.stop
+                );
+            }
+            arrival.time.checked_sub(stop?.seconds_from_home.into())
+        })
+        .min()
+}
+
+/// Time until the next fetch of the arrivals: short when a bus has to be
+/// caught soon, long when the next one is far away, or there is none
+pub fn interval(
+    config: &Config,
+    arrivals: &[ArrivalTime],
+    logged: &mut BTreeSet<String>,
+) -> Duration {
+    let polling = &config.polling;
+    let fast = polling.fast_secs.max(1) as u64;
+    let slow = (polling.slow_secs as u64).max(fast);
+    let soon = polling.soon_secs as u64;
+
+    let secs = match time_to_leave(config, arrivals, logged) {
+        // Wake up in time to follow it once it gets close
+        Some(leave) if leave > soon => (leave - soon).clamp(fast, slow),
+        Some(_) => fast,
+        None => slow,
+    };
+    Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arrival(stop: &str, time: u64) -> ArrivalTime {
+        ArrivalTime {
+            time,
+            stop: stop.to_string(),
+            line: "27".to_string(),
+            destination: "PLAZA CASTILLA".to_string(),
+        }
+    }
+
+    #[test]
+    fn buses_that_cant_be_caught_are_skipped() {
+        // 874 is 5 minutes from home, 1455 is 3
+        let config = Config::default();
+        let arrivals = [arrival("874", 4 * 60), arrival("1455", 10 * 60)];
+
+        assert_eq!(
+            time_to_leave(&config, &arrivals, &mut BTreeSet::new()),
+            Some(7 * 60)
+        );
+    }
+
+    #[test]
+    fn unconfigured_stops_are_skipped() {
+        let config = Config::default();
+        let mut logged = BTreeSet::new();
+
+        assert_eq!(
+            time_to_leave(&config, &[arrival("999", 60)], &mut logged),
+            None
+        );
+        assert_eq!(
+            time_to_leave(
+                &config,
+                &[arrival("999", 60), arrival("874", 6 * 60)],
+                &mut logged
+            ),
+            Some(60)
+        );
+        assert_eq!(
+            interval(&config, &[arrival("999", 60)], &mut logged),
+            Duration::from_secs(config.polling.slow_secs as u64)
+        );
+    }
+
+    #[test]
+    fn each_unconfigured_stop_is_logged() {
+        let config = Config::default();
+        let mut logged = BTreeSet::new();
+
+        time_to_leave(&config, &[arrival("999", 60)], &mut logged);
+        time_to_leave(
+            &config,
+            &[arrival("999", 60), arrival("123", 60), arrival("874", 60)],
+            &mut logged,
+        );
+        let stops: Vec<&str> = logged.iter().map(String::as_str).collect();
+        assert_eq!(stops, ["123", "999"]);
+    }
+
+    #[test]
+    fn far_away_buses_are_polled_slowly() {
+        let config = Config::default();
+        let polling = &config.polling;
+        let slow = Duration::from_secs(polling.slow_secs as u64);
+
+        // An hour to leave home, clamped to the slow interval
+        let arrivals = [arrival("874", 5 * 60 + 3600)];
+        assert_eq!(interval(&config, &arrivals, &mut BTreeSet::new()), slow);
+
+        // Closer, woken up when it is about to be soon
+        let leave = polling.soon_secs as u64 + polling.fast_secs as u64 + 10;
+        let arrivals = [arrival("874", 5 * 60 + leave)];
+        assert_eq!(
+            interval(&config, &arrivals, &mut BTreeSet::new()),
+            Duration::from_secs(leave - polling.soon_secs as u64)
+        );
+    }
+
+    #[test]
+    fn buses_to_catch_soon_are_polled_fast() {
+        let config = Config::default();
+        let polling = &config.polling;
+        let fast = Duration::from_secs(polling.fast_secs as u64);
+
+        let arrivals = [arrival("874", 5 * 60 + polling.soon_secs as u64)];
+        assert_eq!(interval(&config, &arrivals, &mut BTreeSet::new()), fast);
+        let arrivals = [arrival("874", 5 * 60)];
+        assert_eq!(interval(&config, &arrivals, &mut BTreeSet::new()), fast);
+    }
+}
@@ -0,0 +1,756 @@
+pub mod dns;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use log::*;
+
+use crate::clock::TimeZone;
+use crate::config::{Config, DestinationConfig, LineConfig, NetworkConfig, StopConfig};
+
+/// Name of the SoftAP started while provisioning
+pub const AP_SSID: &str = "busmonitor";
+
+/// Address of the device on its own SoftAP (ESP-IDF default)
+pub const PORTAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How often the listener is checked for new connections
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// Wi-Fi networks that can be saved from the form
+const MAX_NETWORKS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub location: Option<String>,
+    pub body: String,
+}
+
+impl Reply {
+    fn html(status: u16, body: String) -> Self {
+        Reply {
+            status,
+            location: None,
+            body,
+        }
+    }
+
+    fn redirect(location: String) -> Self {
+        Reply {
+            status: 302,
+            location: Some(location),
+            body: String::new(),
+        }
+    }
+}
+
+/// Handles one portal request, returning the reply and, when the form was
+/// submitted successfully, the new configuration to store.
+pub fn handle_request(
+    method: &str,
+    path: &str,
+    body: &str,
+    config: &Config,
+) -> (Reply, Option<Config>) {
+    match (method, path) {
+        ("GET", "/") => (
+            Reply::html(200, render_form(&Form::stored(config), None)),
+            None,
+        ),
+        ("POST", "/save") => match apply_form(config, body) {
+            Ok(new_config) => (
+                Reply::html(200, page("<p>Saved, restarting the bus monitor...</p>")),
+                Some(new_config),
+            ),
+            // Shown again as submitted, to fix what is wrong
+            Err(e) => (
+                Reply::html(
+                    400,
+                    render_form(&Form::submitted(config, body), Some(&e.to_string())),
+                ),
+                None,
+            ),
+        },
+        // Anything else (OS connectivity checks included) goes to the form,
+        // that is what makes phones pop up the captive portal
+        _ => (Reply::redirect(format!("http://{}/", PORTAL_IP)), None),
+    }
+}
+
+/// Serves the portal until a configuration is submitted, which is handed to
+/// `on_save` before returning `true`, or until no request came for
+/// `idle_timeout`, returning `false`.
+pub fn serve(
+    listener: TcpListener,
+    config: &Config,
+    idle_timeout: Option<Duration>,
+    mut on_save: impl FnMut(Config) -> Result<()>,
+) -> Result<bool> {
+    // Polled, a blocking accept can't give up when nobody shows up
+    listener.set_nonblocking(true)?;
+    let mut last_request = Instant::now();
+
+    loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if idle_timeout.map_or(false, |timeout| last_request.elapsed() >= timeout) {
+                    info!("Provisioning portal idle, giving up");
+                    return Ok(false);
+                }
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        last_request = Instant::now();
+
+        stream.set_nonblocking(false)?;
+        match serve_connection(&mut stream, config) {
+            Ok(Some(new_config)) => {
+                info!("Provisioning form submitted");
+                on_save(new_config)?;
+                return Ok(true);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Provisioning request failed: {}", e),
+        }
+    }
+}
+
+fn serve_connection(stream: &mut TcpStream, config: &Config) -> Result<Option<Config>> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let (method, path, body) = read_request(stream)?;
+    debug!("Provisioning request {} {}", method, path);
+
+    let (reply, new_config) = handle_request(&method, &path, &body, config);
+    write_reply(stream, &reply)?;
+
+    Ok(new_config)
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<(String, String, String)> {
+    let mut raw = Vec::new();
+    let mut chunk = [0_u8; 512];
+
+    let header_end = loop {
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            bail!("Connection closed before the request was complete");
+        }
+        raw.extend_from_slice(&chunk[..len]);
+        if let Some(pos) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if raw.len() > MAX_REQUEST_SIZE {
+            bail!("Request headers too large");
+        }
+    };
+
+    let head = String::from_utf8_lossy(&raw[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("/").to_string();
+
+    let content_length = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    if content_length > MAX_REQUEST_SIZE {
+        bail!("Request body too large");
+    }
+
+    let mut body = raw[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok((method, path, String::from_utf8_lossy(&body).to_string()))
+}
+
+fn write_reply(stream: &mut TcpStream, reply: &Reply) -> Result<()> {
+    let reason = match reply.status {
+        200 => "OK",
+        302 => "Found",
+        400 => "Bad Request",
+        _ => "",
+    };
+
+    let mut head = format!("HTTP/1.0 {} {}\r\n", reply.status, reason);
+    if let Some(location) = &reply.location {
+        head.push_str(&format!("Location: {}\r\n", location));
+    }
+    head.push_str(&format!(
+        "Content-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reply.body.len()
+    ));
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(reply.body.as_bytes())?;
+    Ok(())
+}
+
+fn page(content: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>Bus monitor setup</title></head><body><h1>Bus monitor setup</h1>{}</body></html>",
+        content
+    )
+}
+
+/// Text of the form fields, as stored or as submitted
+struct Form {
+    networks: Vec<NetworkFields>,
+    emt_user: String,
+    stops: String,
+    destinations: String,
+    lines: String,
+    timezone: String,
+}
+
+#[derive(Default)]
+struct NetworkFields {
+    ssid: String,
+    /// Only what was just submitted, stored passwords are never sent back
+    pass: String,
+    priority: String,
+    /// A password is stored for the SSID, an empty one keeps it
+    stored: bool,
+}
+
+impl Form {
+    fn stored(config: &Config) -> Self {
+        let networks = config
+            .networks
+            .iter()
+            .map(|n| NetworkFields {
+                ssid: n.ssid.clone(),
+                pass: String::new(),
+                priority: n.priority.to_string(),
+                stored: true,
+            })
+            .collect();
+
+        let mut stops = String::new();
+        for s in &config.stops {
+            let _ = writeln!(stops, "{} {} {}", s.id, s.label, s.seconds_from_home / 60);
+        }
+
+        let mut destinations = String::new();
+        for d in &config.destinations {
+            let icon = if d.icon.is_empty() { "-" } else { &d.icon };
+            let _ = writeln!(destinations, "{} {}", d.name, icon);
+        }
+
+        let mut lines = String::new();
+        for l in &config.lines {
+            let _ = write!(lines, "{}", l.name);
+            for d in &config.destinations {
+                let _ = match l.seconds_to(&d.name) {
+                    Some(secs) => write!(lines, " {}", secs / 60),
+                    None => write!(lines, " -"),
+                };
+            }
+            lines.push('\n');
+        }
+
+        Form {
+            networks,
+            emt_user: config.emt_user.clone(),
+            stops,
+            destinations,
+            lines,
+            timezone: config.timezone.clone(),
+        }
+    }
+
+    /// The fields of the submitted `body`, the stored ones where missing
+    fn submitted(config: &Config, body: &str) -> Self {
+        let mut form = Form::stored(config);
+        let mut networks = BTreeMap::<usize, NetworkFields>::new();
+
+        for (name, value) in parse_form(body) {
+            if let Some((field, idx)) = name.strip_prefix("wifi_").and_then(|n| n.split_once('_')) {
+                let idx = idx.parse::<usize>().unwrap_or(MAX_NETWORKS);
+                if idx >= MAX_NETWORKS {
+                    continue;
+                }
+                let network = networks.entry(idx).or_default();
+                match field {
+                    "ssid" => network.ssid = value,
+                    "pass" => network.pass = value,
+                    "priority" => network.priority = value,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match name.as_str() {
+                "emt_user" => form.emt_user = value,
+                "stops" => form.stops = value,
+                "destinations" => form.destinations = value,
+                "lines" => form.lines = value,
+                "timezone" => form.timezone = value,
+                _ => {}
+            }
+        }
+
+        if !networks.is_empty() {
+            form.networks = networks
+                .into_values()
+                .filter(|n| !n.ssid.trim().is_empty())
+                .map(|mut network| {
+                    network.stored = config
+                        .networks
+                        .iter()
+                        .any(|n| n.ssid == network.ssid.trim());
+                    network
+                })
+                .collect();
+        }
+        form
+    }
+}
+
+fn render_form(form: &Form, error: Option<&str>) -> String {
+    // The networks and a blank one to add
+    let mut networks = String::new();
+    for idx in 0..(form.networks.len() + 1).min(MAX_NETWORKS) {
+        let network = form.networks.get(idx);
+        let _ = write!(
+            networks,
+            "<p>SSID <input name=\"wifi_ssid_{idx}\" value=\"{ssid}\"> \
+             Password <input name=\"wifi_pass_{idx}\" type=\"password\" value=\"{pass}\" placeholder=\"{placeholder}\"> \
+             Priority <input name=\"wifi_priority_{idx}\" value=\"{priority}\" size=\"2\"></p>",
+            idx = idx,
+            ssid = escape(network.map_or("", |n| n.ssid.as_str())),
+            pass = escape(network.map_or("", |n| n.pass.as_str())),
+            placeholder = if network.map_or(false, |n| n.stored) {
+                "unchanged"
+            } else {
+                ""
+            },
+            priority = escape(network.map_or("0", |n| n.priority.as_str())),
+        );
+    }
+
+    let error = error
+        .map(|e| format!("<p style=\"color:red\">{}</p>", escape(e)))
+        .unwrap_or_default();
+
+    page(&format!(
+        "{error}<form method=\"post\" action=\"/save\">\
+         <h2>Wi-Fi</h2>\
+         <p>The networks in range are joined by priority (highest first), then signal \
+         strength. Clear the SSID to forget a network.</p>\
+         {networks}\
+         <h2>EMT Madrid</h2>\
+         <p>Email <input name=\"emt_user\" value=\"{user}\"></p>\
+         <p>Password <input name=\"emt_pass\" type=\"password\" placeholder=\"unchanged\"></p>\
+         <h2>Routes</h2>\
+         <p>One line per stop: stop number, one word label, minutes to walk there</p>\
+         <p><textarea name=\"stops\" rows=\"4\" cols=\"30\">{stops}</textarea></p>\
+         <p>One line per destination: one word name, icon (school, work, bus or -)</p>\
+         <p><textarea name=\"destinations\" rows=\"4\" cols=\"30\">{destinations}</textarea></p>\
+         <p>One line per bus: line, then the minutes to each destination in the order \
+         above, - if the bus does not go there</p>\
+         <p><textarea name=\"lines\" rows=\"8\" cols=\"30\">{lines}</textarea></p>\
+         <h2>Time zone</h2>\
+         <p>POSIX TZ rules <input name=\"timezone\" value=\"{timezone}\"></p>\
+         <p><input type=\"submit\" value=\"Save\"></p></form>",
+        error = error,
+        networks = networks,
+        user = escape(&form.emt_user),
+        stops = escape(&form.stops),
+        destinations = escape(&form.destinations),
+        lines = escape(&form.lines),
+        timezone = escape(&form.timezone),
+    ))
+}
+
+/// Builds the new configuration out of the submitted form, empty password
+/// fields keep the stored passwords.
+fn apply_form(config: &Config, body: &str) -> Result<Config> {
+    let mut new_config = config.clone();
+    let mut lines = None;
+    let mut networks = BTreeMap::<usize, NetworkConfig>::new();
+
+    for (name, value) in parse_form(body) {
+        // Passwords may start or end with spaces
+        let value = if name == "emt_pass" || name.starts_with("wifi_pass_") {
+            value
+        } else {
+            value.trim().to_string()
+        };
+
+        if let Some((field, idx)) = name.strip_prefix("wifi_").and_then(|n| n.split_once('_')) {
+            let idx = idx.parse::<usize>().unwrap_or(MAX_NETWORKS);
+            if idx >= MAX_NETWORKS {
+                continue;
+            }
+            let network = networks.entry(idx).or_insert_with(|| NetworkConfig {
+                ssid: String::new(),
+                pass: String::new(),
+                priority: 0,
+            });
+            match field {
+                "ssid" => network.ssid = value,
+                "pass" => network.pass = value,
+                "priority" => {
+                    network.priority = value
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid Wi-Fi priority \"{}\"", value))?
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match name.as_str() {
+            "emt_user" => new_config.emt_user = value,
+            "emt_pass" if !value.is_empty() => new_config.emt_pass = value,
+            "stops" => new_config.stops = parse_stops(&value)?,
+            "destinations" => new_config.destinations = parse_destinations(&value)?,
+            "lines" => lines = Some(value),
+            "timezone" => {
+                TimeZone::parse(&value)
+                    .map_err(|e| anyhow!("Invalid time zone \"{}\": {}", value, e))?;
+                new_config.timezone = value;
+            }
+            _ => {}
+        }
+    }
+
+    // Ride times are given in the order of the (maybe just edited) destinations
+    if let Some(lines) = lines {
+        new_config.lines = parse_lines(&lines, &new_config.destinations)?;
+    }
+
+    new_config.networks = networks
+        .into_values()
+        .filter(|n| !n.ssid.is_empty())
+        .map(|mut network| {
+            // An empty password keeps the stored one
+            if network.pass.is_empty() {
+                if let Some(stored) = config.networks.iter().find(|n| n.ssid == network.ssid) {
+                    network.pass = stored.pass.clone();
+                }
+            }
+            network
+        })
+        .collect();
+
+    if new_config.networks.is_empty() {
+        bail!("At least one Wi-Fi network is required");
+    }
+    if new_config.stops.is_empty() {
+        bail!("At least one stop is required");
+    }
+
+    Ok(new_config)
+}
+
+/// Splits a textarea into rows of exactly `count` whitespace separated fields
+fn parse_rows(text: &str, count: usize) -> Result<Vec<Vec<&str>>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(|row| {
+            let fields: Vec<&str> = row.split_whitespace().collect();
+            if fields.len() != count {
+                bail!("Expected {} fields in \"{}\"", count, row);
+            }
+            Ok(fields)
+        })
+        .collect()
+}
+
+fn minutes(s: &str) -> Result<u32> {
+    s.parse::<u32>()
+        .map(|m| m * 60)
+        .map_err(|_| anyhow!("Invalid number of minutes \"{}\"", s))
+}
+
+fn parse_stops(text: &str) -> Result<Vec<StopConfig>> {
+    parse_rows(text, 3)?
+        .into_iter()
+        .map(|fields| {
+            Ok(StopConfig {
+                id: fields[0].to_string(),
+                label: fields[1].to_string(),
+                seconds_from_home: minutes(fields[2])?,
+            })
+        })
+        .collect()
+}
+
+fn parse_destinations(text: &str) -> Result<Vec<DestinationConfig>> {
+    parse_rows(text, 2)?
+        .into_iter()
+        .map(|fields| {
+            Ok(DestinationConfig {
+                name: fields[0].to_string(),
+                icon: fields[1].trim_start_matches('-').to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_lines(text: &str, destinations: &[DestinationConfig]) -> Result<Vec<LineConfig>> {
+    parse_rows(text, destinations.len() + 1)?
+        .into_iter()
+        .map(|fields| {
+            let mut seconds_to = BTreeMap::new();
+            for (destination, field) in destinations.iter().zip(&fields[1..]) {
+                if *field != "-" {
+                    seconds_to.insert(destination.name.clone(), minutes(field)?);
+                }
+            }
+
+            Ok(LineConfig {
+                name: fields[0].to_string(),
+                seconds_to,
+            })
+        })
+        .collect()
+}
+
+/// Decodes an `application/x-www-form-urlencoded` body
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(name), url_decode(value))
+        })
+        .collect()
+}
+
+fn url_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = input.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|h| h.len() == 2 && h.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(decoded) => bytes.push(decoded),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend_from_slice(&hex);
+                    }
+                }
+            }
+            _ => bytes.push(b),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            networks: vec![NetworkConfig {
+                ssid: "home".to_string(),
+                pass: "secret".to_string(),
+                priority: 1,
+            }],
+            ..Config::default()
+        }
+    }
+
+    const VALID_FORM: &str = "wifi_ssid_0=home&wifi_pass_0=&wifi_priority_0=1\
+        &wifi_ssid_1=Caf%C3%A9+Wifi&wifi_pass_1=p%26ss&wifi_priority_1=0\
+        &emt_user=user%40example.com&emt_pass=\
+        &stops=874+Home+5%0D%0A1455+Work+3&destinations=Sol+-\
+        &lines=27+20%0D%0A45+-&timezone=CET-1CEST%2CM3.5.0%2CM10.5.0%2F3";
+
+    /// Sends a raw request to the portal, returns the status and the reply
+    fn request(addr: std::net::SocketAddr, request: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+
+        let status = reply.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, reply)
+    }
+
+    fn post(addr: std::net::SocketAddr, body: &str) -> (u16, String) {
+        request(
+            addr,
+            &format!(
+                "POST /save HTTP/1.1\r\nHost: 192.168.71.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    #[test]
+    fn serves_the_portal_until_saved() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            serve(listener, &config(), None, |new_config| {
+                tx.send(new_config).unwrap();
+                Ok(())
+            })
+        });
+
+        let (status, reply) = request(addr, "GET / HTTP/1.1\r\nHost: 192.168.71.1\r\n\r\n");
+        assert_eq!(status, 200);
+        assert!(reply.contains("name=\"wifi_ssid_0\" value=\"home\""));
+        assert!(!reply.contains("secret"));
+
+        let (status, reply) = request(
+            addr,
+            "GET /generate_204 HTTP/1.1\r\nHost: connectivitycheck.gstatic.com\r\n\r\n",
+        );
+        assert_eq!(status, 302);
+        assert!(reply.contains("Location: http://192.168.71.1/\r\n"));
+
+        let (status, _) = post(addr, VALID_FORM);
+        assert_eq!(status, 200);
+        assert!(server.join().unwrap().unwrap());
+
+        let saved = rx.try_recv().unwrap();
+        assert_eq!(
+            saved.networks,
+            vec![
+                NetworkConfig {
+                    ssid: "home".to_string(),
+                    pass: "secret".to_string(),
+                    priority: 1,
+                },
+                NetworkConfig {
+                    ssid: "Café Wifi".to_string(),
+                    pass: "p&ss".to_string(),
+                    priority: 0,
+                },
+            ]
+        );
+        assert_eq!(saved.emt_user, "user@example.com");
+        assert_eq!(saved.emt_pass, config().emt_pass);
+        assert_eq!(saved.stops.len(), 2);
+        assert_eq!(saved.stops[1].seconds_from_home, 3 * 60);
+        assert_eq!(saved.lines[0].seconds_to("Sol"), Some(20 * 60));
+        assert_eq!(saved.lines[1].seconds_to("Sol"), None);
+        assert_eq!(saved.timezone, "CET-1CEST,M3.5.0,M10.5.0/3");
+    }
+
+    #[test]
+    fn invalid_form_is_shown_again_as_submitted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, &config(), None, |_| panic!("Invalid form saved")));
+
+        let body = VALID_FORM.replace("874+Home+5", "874+Home+soon");
+        let (status, reply) = post(addr, &body);
+        assert_eq!(status, 400);
+        assert!(reply.contains("Invalid number of minutes &quot;soon&quot;"));
+        // What was submitted, not what is stored
+        assert!(reply.contains("874 Home soon\r\n1455 Work 3</textarea>"));
+        assert!(reply.contains("value=\"Café Wifi\""));
+        assert!(reply.contains("value=\"p&amp;ss\""));
+        assert!(reply.contains("value=\"user@example.com\""));
+    }
+
+    #[test]
+    fn idle_portal_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let saved = serve(
+            listener,
+            &config(),
+            Some(Duration::from_millis(200)),
+            |_| panic!("Nothing was submitted"),
+        );
+        assert!(!saved.unwrap());
+    }
+
+    #[test]
+    fn passwords_are_not_trimmed() {
+        let body = VALID_FORM
+            .replace("wifi_pass_0=&", "wifi_pass_0=+pass+word+&")
+            .replace("emt_pass=&", "emt_pass=secret+&")
+            .replace("emt_user=user", "emt_user=+user");
+        let new_config = apply_form(&config(), &body).unwrap();
+        assert_eq!(new_config.networks[0].pass, " pass word ");
+        assert_eq!(new_config.emt_pass, "secret ");
+        assert_eq!(new_config.emt_user, "user@example.com");
+    }
+
+    #[test]
+    fn stored_passwords_are_kept_but_not_shown() {
+        let form = Form::submitted(&config(), "wifi_ssid_0=home&wifi_pass_0=");
+        assert_eq!(form.networks.len(), 1);
+        assert!(form.networks[0].stored);
+        assert!(!render_form(&form, None).contains("secret"));
+    }
+
+    #[test]
+    fn form_is_decoded() {
+        assert_eq!(
+            parse_form("a=1&b=two+words&&c&d=%3D%26"),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "two words".to_string()),
+                ("c".to_string(), String::new()),
+                ("d".to_string(), "=&".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_are_kept() {
+        assert_eq!(url_decode("Caf%C3%A9"), "Café");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%zz%+4%4"), "%zz%+4%4");
+        assert_eq!(url_decode("%FF"), "\u{fffd}");
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            escape("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::{Ipv4Addr, UdpSocket};
+
+use anyhow::Result;
+use log::*;
+
+const HEADER_SIZE: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL_SECS: u32 = 60;
+
+/// Answers every DNS query for an A record with `ip`, so any name a client
+/// looks up while connected to the SoftAP resolves to the portal.
+pub fn serve(socket: UdpSocket, ip: Ipv4Addr) -> Result<()> {
+    let mut buf = [0_u8; 512];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        match reply(&buf[..len], ip) {
+            Some(answer) => {
+                socket.send_to(&answer, peer)?;
+            }
+            None => debug!("Ignoring malformed DNS query from {}", peer),
+        }
+    }
+}
+
+/// Builds the reply to a single-question DNS query
+pub fn reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    // Only standard queries with exactly one question are answered
+    if flags & 0x8000 != 0 || (flags >> 11) & 0xf != 0 || questions != 1 {
+        return None;
+    }
+
+    // Walk the labels of the question name up to the terminating zero
+    let mut pos = HEADER_SIZE;
+    loop {
+        let len = *query.get(pos)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len + 1;
+    }
+    let question_end = pos + 5;
+    let qtype = u16::from_be_bytes([*query.get(pos + 1)?, *query.get(pos + 2)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 3)?, *query.get(pos + 4)?]);
+
+    let answer = qtype == TYPE_A && qclass == CLASS_IN;
+
+    let mut reply = Vec::with_capacity(question_end + 16);
+    reply.extend_from_slice(&query[0..2]); // id
+    reply.extend_from_slice(&(0x8080 | (flags & 0x0100)).to_be_bytes()); // response, RD copied, RA
+    reply.extend_from_slice(&1_u16.to_be_bytes()); // questions
+    reply.extend_from_slice(&(answer as u16).to_be_bytes()); // answers
+    reply.extend_from_slice(&0_u32.to_be_bytes()); // authority and additional records
+    reply.extend_from_slice(&query[HEADER_SIZE..question_end]);
+
+    if answer {
+        reply.extend_from_slice(&0xc00c_u16.to_be_bytes()); // pointer to the question name
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL_SECS.to_be_bytes());
+        reply.extend_from_slice(&4_u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// Query for `example.com` with the given id, type and class
+    fn query(qtype: u16, qclass: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&qclass.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn a_queries_are_answered_with_the_portal() {
+        let query = query(TYPE_A, CLASS_IN);
+        let reply = reply(&query, IP).unwrap();
+
+        assert_eq!(
+            &reply[..12],
+            &[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(&reply[12..query.len()], &query[12..]);
+        assert_eq!(
+            &reply[query.len()..],
+            &[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn other_queries_get_no_answers() {
+        let aaaa = query(28, CLASS_IN);
+        let reply = reply(&aaaa, IP).unwrap();
+
+        assert_eq!(&reply[4..8], &[0, 1, 0, 0]);
+        assert_eq!(reply.len(), aaaa.len());
+    }
+
+    #[test]
+    fn malformed_queries_are_ignored() {
+        let valid = query(TYPE_A, CLASS_IN);
+
+        // Too short, cut in the name or the type
+        assert_eq!(reply(&valid[..11], IP), None);
+        assert_eq!(reply(&valid[..18], IP), None);
+        assert_eq!(reply(&valid[..valid.len() - 1], IP), None);
+
+        // A response, an inverse query, two questions
+        for (offset, value) in [(2, 0x81), (2, 0x09), (5, 2)] {
+            let mut query = valid.clone();
+            query[offset] = value;
+            assert_eq!(reply(&query, IP), None);
+        }
+
+        // Compressed names don't belong in a question
+        let mut compressed = valid[..12].to_vec();
+        compressed.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        assert_eq!(reply(&compressed, IP), None);
+    }
+}
@@ -0,0 +1,370 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Result};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime, Time, Weekday};
+
+use crate::clock::TimeZone;
+use crate::config::WindowConfig;
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Monday),
+    ("tue", Weekday::Tuesday),
+    ("wed", Weekday::Wednesday),
+    ("thu", Weekday::Thursday),
+    ("fri", Weekday::Friday),
+    ("sat", Weekday::Saturday),
+    ("sun", Weekday::Sunday),
+];
+
+/// A time of the week the monitor is active
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    days: Vec<Weekday>,
+    start: Time,
+    end: Time,
+}
+
+impl Window {
+    fn contains(&self, local: OffsetDateTime) -> bool {
+        self.days.contains(&local.weekday())
+            && self.start <= local.time()
+            && local.time() < self.end
+    }
+}
+
+/// The times of the week the monitor is active, it sleeps the rest of the
+/// time. An empty schedule is always active.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    timezone: TimeZone,
+}
+
+impl Schedule {
+    pub fn new(windows: &[WindowConfig], timezone: TimeZone) -> Result<Self> {
+        let windows = windows
+            .iter()
+            .map(|w| {
+                let window = Window {
+                    days: parse_days(&w.days)?,
+                    start: parse_time(&w.start)?,
+                    end: parse_time(&w.end)?,
+                };
+                if window.end <= window.start {
+                    bail!("Window {}-{} ends before it starts", w.start, w.end);
+                }
+                Ok(window)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Schedule { windows, timezone })
+    }
+
+    pub fn is_active(&self, time: OffsetDateTime) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+
+        let local = self.timezone.to_local(time);
+        self.windows.iter().any(|w| w.contains(local))
+    }
+
+    /// End of the window `time` is in, the latest one if they overlap. None
+    /// outside them, or for an empty schedule.
+    pub fn window_end(&self, time: OffsetDateTime) -> Option<OffsetDateTime> {
+        let local = self.timezone.to_local(time);
+        self.windows
+            .iter()
+            .filter(|w| w.contains(local))
+            .map(|w| {
+                self.timezone
+                    .instant(PrimitiveDateTime::new(local.date(), w.end))
+            })
+            .max()
+    }
+
+    /// Start of the next window after `time`, none for an empty schedule
+    pub fn next_start(&self, time: OffsetDateTime) -> Option<OffsetDateTime> {
+        let local = self.timezone.to_local(time);
+
+        // A week and a day covers a window earlier today, next week
+        (0..=7)
+            .map(|days| local.date() + Duration::days(days))
+            .flat_map(|date| {
+                self.windows
+                    .iter()
+                    .filter(move |w| w.days.contains(&date.weekday()))
+                    .map(move |w| self.timezone.instant(PrimitiveDateTime::new(date, w.start)))
+            })
+            .filter(|start| *start > time)
+            .min()
+    }
+}
+
+/// Time left at `now` until the end of the active window or of the on demand
+/// time, whichever is later. Without either, or a clock to tell the window,
+/// just `fallback`.
+pub fn active_time_left(
+    schedule: &Schedule,
+    now: OffsetDateTime,
+    time_synced: bool,
+    on_demand_left: Option<std::time::Duration>,
+    fallback: std::time::Duration,
+) -> std::time::Duration {
+    let window_left = schedule
+        .window_end(now)
+        .filter(|_| time_synced)
+        .map(|end| std::time::Duration::try_from(end - now).unwrap_or_default());
+    let on_demand_left = on_demand_left.filter(|left| !left.is_zero());
+
+    window_left.max(on_demand_left).unwrap_or(fallback)
+}
+
+/// `HH:MM`
+fn parse_time(s: &str) -> Result<Time> {
+    let (hour, minute) = s
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid time \"{}\", expected HH:MM", s))?;
+    let hour = hour
+        .parse()
+        .map_err(|_| anyhow!("Invalid hour in \"{}\"", s))?;
+    let minute = minute
+        .parse()
+        .map_err(|_| anyhow!("Invalid minute in \"{}\"", s))?;
+    Ok(Time::from_hms(hour, minute, 0)?)
+}
+
+fn parse_weekday(s: &str) -> Result<usize> {
+    let name = s.trim().to_lowercase();
+    WEEKDAYS
+        .iter()
+        .position(|(day, _)| name.starts_with(day))
+        .ok_or_else(|| anyhow!("Invalid day \"{}\"", s))
+}
+
+/// Comma separated days or ranges of days, e.g. `mon-fri` or `sat,sun`
+fn parse_days(s: &str) -> Result<Vec<Weekday>> {
+    let mut days = Vec::new();
+    for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_weekday(first)?, parse_weekday(last)?),
+            None => {
+                let day = parse_weekday(part)?;
+                (day, day)
+            }
+        };
+
+        // Ranges may wrap around the week, e.g. fri-mon
+        let mut day = first;
+        loop {
+            days.push(WEEKDAYS[day].1);
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+
+    if days.is_empty() {
+        bail!("No days in \"{}\"", s);
+    }
+    Ok(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Month;
+
+    use super::*;
+
+    fn window(days: &str, start: &str, end: &str) -> WindowConfig {
+        WindowConfig {
+            days: days.to_string(),
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    /// UTC time in 2023
+    fn utc(month: Month, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        time::Date::from_calendar_date(2023, month, day)
+            .unwrap()
+            .with_time(Time::from_hms(hour, minute, 0).unwrap())
+            .assume_utc()
+    }
+
+    /// UTC time on Monday 2023-01-16, Madrid is an hour ahead
+    fn monday(hour: u8, minute: u8) -> OffsetDateTime {
+        utc(Month::January, 16, hour, minute)
+    }
+
+    /// The default schedule, weekdays 07:30-09:00 and 13:30-15:00
+    fn weekdays() -> Schedule {
+        Schedule::new(
+            &[
+                window("mon-fri", "07:30", "09:00"),
+                window("mon-fri", "13:30", "15:00"),
+            ],
+            TimeZone::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn window_end_is_the_one_of_the_active_window() {
+        let schedule = Schedule::new(
+            &[
+                window("mon-fri", "07:30", "09:00"),
+                window("mon", "08:00", "09:30"),
+                window("mon-fri", "13:30", "15:00"),
+            ],
+            TimeZone::default(),
+        )
+        .unwrap();
+
+        assert_eq!(schedule.window_end(monday(6, 45)), Some(monday(8, 0)));
+        // Overlapping windows end with the last one
+        assert_eq!(schedule.window_end(monday(7, 15)), Some(monday(8, 30)));
+        assert_eq!(schedule.window_end(monday(13, 59)), Some(monday(14, 0)));
+        assert_eq!(schedule.window_end(monday(8, 30)), None);
+        assert_eq!(schedule.window_end(monday(14, 0)), None);
+    }
+
+    #[test]
+    fn empty_schedule_never_ends() {
+        let schedule = Schedule::new(&[], TimeZone::default()).unwrap();
+
+        assert!(schedule.is_active(monday(3, 0)));
+        assert_eq!(schedule.window_end(monday(3, 0)), None);
+        assert_eq!(schedule.next_start(monday(3, 0)), None);
+    }
+
+    #[test]
+    fn next_start_later_today() {
+        assert_eq!(weekdays().next_start(monday(9, 0)), Some(monday(12, 30)));
+        // Not the window that just started
+        assert_eq!(weekdays().next_start(monday(6, 30)), Some(monday(12, 30)));
+    }
+
+    #[test]
+    fn next_start_next_day() {
+        assert_eq!(
+            weekdays().next_start(monday(15, 0)),
+            Some(utc(Month::January, 17, 6, 30))
+        );
+    }
+
+    #[test]
+    fn next_start_after_the_weekend() {
+        // Friday evening, nothing until Monday morning
+        assert_eq!(
+            weekdays().next_start(utc(Month::January, 20, 18, 0)),
+            Some(utc(Month::January, 23, 6, 30))
+        );
+    }
+
+    #[test]
+    fn next_start_across_the_dst_change() {
+        // Clocks go forward on Sunday 2023-03-26, Monday 07:30 is then two
+        // hours ahead of UTC
+        assert_eq!(
+            weekdays().next_start(utc(Month::March, 24, 18, 0)),
+            Some(utc(Month::March, 27, 5, 30))
+        );
+    }
+
+    #[test]
+    fn active_time_left_is_the_longest_of_window_and_on_demand() {
+        let schedule = weekdays();
+        let fallback = std::time::Duration::from_secs(60);
+        let minutes = |m: u64| std::time::Duration::from_secs(m * 60);
+
+        // 08:00 local, the window ends at 09:00
+        let now = monday(7, 0);
+        assert_eq!(
+            active_time_left(&schedule, now, true, None, fallback),
+            minutes(60)
+        );
+        assert_eq!(
+            active_time_left(&schedule, now, true, Some(minutes(90)), fallback),
+            minutes(90)
+        );
+        assert_eq!(
+            active_time_left(&schedule, now, true, Some(minutes(5)), fallback),
+            minutes(60)
+        );
+    }
+
+    #[test]
+    fn active_time_left_falls_back_without_a_window() {
+        let schedule = weekdays();
+        let fallback = std::time::Duration::from_secs(60);
+
+        // Outside the windows
+        assert_eq!(
+            active_time_left(&schedule, monday(10, 0), true, None, fallback),
+            fallback
+        );
+        // Or with a clock that can't tell the window
+        assert_eq!(
+            active_time_left(&schedule, monday(7, 0), false, None, fallback),
+            fallback
+        );
+        // On demand time that is over
+        assert_eq!(
+            active_time_left(
+                &schedule,
+                monday(10, 0),
+                true,
+                Some(std::time::Duration::ZERO),
+                fallback
+            ),
+            fallback
+        );
+    }
+
+    #[test]
+    fn day_ranges_wrap_around_the_week() {
+        assert_eq!(
+            parse_days("fri-mon").unwrap(),
+            [
+                Weekday::Friday,
+                Weekday::Saturday,
+                Weekday::Sunday,
+                Weekday::Monday
+            ]
+        );
+        assert_eq!(
+            parse_days("Sat, sun").unwrap(),
+            [Weekday::Saturday, Weekday::Sunday]
+        );
+    }
+
+    #[test]
+    fn invalid_days_are_errors() {
+        for days in ["", ",", "funday", "mon-", "mon-xyz,fri"] {
+            assert!(parse_days(days).is_err(), "{:?} was parsed", days);
+        }
+    }
+
+    #[test]
+    fn times_are_parsed() {
+        assert_eq!(
+            parse_time(" 07:30").unwrap(),
+            Time::from_hms(7, 30, 0).unwrap()
+        );
+        for time in ["7.30", "07", "ab:30", "07:cd", "24:00", "07:60", "-1:00"] {
+            assert!(parse_time(time).is_err(), "{:?} was parsed", time);
+        }
+    }
+
+    #[test]
+    fn windows_must_end_after_they_start() {
+        for (start, end) in [("09:00", "09:00"), ("09:00", "07:30")] {
+            let result = Schedule::new(&[window("mon", start, end)], TimeZone::default());
+            assert!(result.is_err(), "{}-{} was accepted", start, end);
+        }
+        assert!(Schedule::new(&[window("mon", "07:30", "7.45")], TimeZone::default()).is_err());
+    }
+}
@@ -1,57 +1,156 @@
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
 use std::time::Duration;
 
 use anyhow::{bail, Result};
+use embedded_svc::storage::RawStorage;
 use embedded_svc::wifi::*;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
 use esp_idf_svc::wifi::*;
 use esp_idf_svc::{netif::EspNetifStack, nvs::EspDefaultNvs, sysloop::EspSysLoopStack};
 use log::*;
 
-const SSID: &str = env!("RUST_ESP32_STD_DEMO_WIFI_SSID");
-const PASS: &str = env!("RUST_ESP32_STD_DEMO_WIFI_PASS");
+use crate::backoff::Backoff;
+use crate::config::NetworkConfig;
+use crate::peripherals::display::{DisplayMessage, WifiStatus};
+use crate::provisioning::AP_SSID;
 
-pub fn setup_wifi() -> Result<Box<EspWifi>, anyhow::Error> {
+/// How often the supervisor checks the link
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// First and shortest wait between reconnection attempts
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+/// Longest wait between reconnection attempts, the backoff stops doubling
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Signal changes smaller than this (dBm) are not sent to the display
+const RSSI_HYSTERESIS: f32 = 3.0;
+
+/// Name of the NVS entry holding the SSID of the last network joined
+const LAST_SSID_KEY: &str = "last_ssid";
+
+/// The saved networks, and the one joined last time which is tried first
+pub struct Networks {
+    networks: Vec<NetworkConfig>,
+    storage: EspNvsStorage,
+    last_ssid: Option<String>,
+}
+
+impl Networks {
+    pub fn new(default_nvs: Arc<EspDefaultNvs>, networks: &[NetworkConfig]) -> Result<Self> {
+        let storage = EspNvsStorage::new_default(default_nvs, "wifi", true)?;
+
+        let mut buf = [0_u8; 33];
+        let last_ssid = storage
+            .get_raw(LAST_SSID_KEY, &mut buf)?
+            .and_then(|ssid| std::str::from_utf8(ssid).ok())
+            .map(String::from);
+
+        Ok(Networks {
+            networks: networks.to_vec(),
+            storage,
+            last_ssid,
+        })
+    }
+
+    /// Joins the best saved network in range. With `quick` the last network
+    /// joined is tried first, without scanning.
+    fn join(&mut self, wifi: &mut EspWifi, quick: bool) -> Result<()> {
+        if quick {
+            let last = self
+                .last_ssid
+                .as_ref()
+                .and_then(|ssid| self.networks.iter().find(|n| &n.ssid == ssid));
+            if let Some(network) = last {
+                match connect(wifi, network, None) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => warn!("Can't join the last Wifi network {}: {}", network.ssid, e),
+                }
+            }
+        }
+
+        // Scanning needs the station started
+        if let Status(ClientStatus::Stopped, _) = wifi.get_status() {
+            wifi.set_configuration(&Configuration::Client(Default::default()))?;
+        }
+
+        info!("Scanning for Wifi networks");
+        let in_range = wifi.scan()?;
+
+        // Saved networks in range, by priority then signal strength (the
+        // driver keeps the negative RSSI in a u8)
+        let mut candidates: Vec<(NetworkConfig, u8, i8)> = self
+            .networks
+            .iter()
+            .filter_map(|n| {
+                in_range
+                    .iter()
+                    .filter(|ap| ap.ssid == n.ssid)
+                    .map(|ap| (n.clone(), ap.channel, ap.signal_strength as i8))
+                    .max_by_key(|(_, _, rssi)| *rssi)
+            })
+            .collect();
+        candidates.sort_by_key(|(n, _, rssi)| std::cmp::Reverse((n.priority, *rssi)));
+
+        for (network, channel, rssi) in candidates {
+            info!(
+                "Found {} on channel {} ({} dBm)",
+                network.ssid, channel, rssi
+            );
+            match connect(wifi, &network, Some(channel)) {
+                Ok(()) => {
+                    self.remember(&network.ssid);
+                    return Ok(());
+                }
+                Err(e) => warn!("Can't join Wifi network {}: {}", network.ssid, e),
+            }
+        }
+
+        bail!("No saved Wifi network in range")
+    }
+
+    fn remember(&mut self, ssid: &str) {
+        if self.last_ssid.as_deref() == Some(ssid) {
+            return;
+        }
+        if let Err(e) = self.storage.put_raw(LAST_SSID_KEY, ssid.as_bytes()) {
+            warn!("Can't save the last Wifi network: {}", e);
+        }
+        self.last_ssid = Some(ssid.to_string());
+    }
+}
+
+/// The Wi-Fi driver and the networks it may join
+pub struct Station {
+    wifi: Box<EspWifi>,
+    networks: Networks,
+}
+
+/// Joins the best of the saved `networks` in range
+pub fn setup_wifi(
+    default_nvs: Arc<EspDefaultNvs>,
+    networks: &[NetworkConfig],
+) -> Result<Station, anyhow::Error> {
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
-    let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
-    let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
-    /*
-       info!("Wifi created, about to scan");
-
-       let ap_infos = wifi.scan()?;
-
-       let ours = ap_infos.into_iter().find(|a| a.ssid == SSID);
-
-       let channel = if let Some(ours) = ours {
-           info!(
-               "Found configured access point {} on channel {}",
-               SSID, ours.channel
-           );
-           Some(ours.channel)
-       } else {
-           info!(
-               "Configured access point {} not found during scanning, will go with unknown channel",
-               SSID
-           );
-           None
-       };
-    */
-    wifi.set_configuration(&Configuration::Client(
-        //Mixed
-        ClientConfiguration {
-            ssid: SSID.into(),
-            password: PASS.into(),
-            channel: None, /* channel */
-            ..Default::default()
-        },
-        /*       AccessPointConfiguration {
-            ssid: "busmonitor".into(),
-            channel: channel.unwrap_or(1),
-            ..Default::default()
-        },
-        */
-    ))?;
+    let mut wifi = Box::new(EspWifi::new(
+        netif_stack,
+        sys_loop_stack,
+        default_nvs.clone(),
+    )?);
+    let mut networks = Networks::new(default_nvs, networks)?;
+    networks.join(&mut wifi, true)?;
+
+    Ok(Station { wifi, networks })
+}
+
+/// (Re)connects the station to `network`, on `channel` when known from a scan
+fn connect(wifi: &mut EspWifi, network: &NetworkConfig, channel: Option<u8>) -> Result<()> {
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.as_str().into(),
+        password: network.pass.as_str().into(),
+        channel,
+        ..Default::default()
+    }))?;
 
     info!("Wifi configuration set, about to get status");
 
@@ -65,7 +164,150 @@ pub fn setup_wifi() -> Result<Box<EspWifi>, anyhow::Error> {
         ApStatus::Stopped, //ApStatus::Started(ApIpStatus::Done),
     ) = status
     {
-        info!("Wifi connected to {} with IP {}", SSID, ip_settings.ip);
+        info!(
+            "Wifi connected to {} with IP {}",
+            network.ssid, ip_settings.ip
+        );
+    } else {
+        bail!("Unexpected Wifi status: {:?}", status);
+    }
+
+    Ok(())
+}
+
+fn is_connected(wifi: &EspWifi) -> bool {
+    matches!(
+        wifi.get_status(),
+        Status(
+            ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_))),
+            _,
+        )
+    )
+}
+
+/// Whether the station is connected, shared with the threads that need the
+/// network
+#[derive(Clone)]
+pub struct Link {
+    up: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Link {
+    fn new(up: bool) -> Self {
+        Link {
+            up: Arc::new((Mutex::new(up), Condvar::new())),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        *self.up.0.lock().unwrap()
+    }
+
+    /// Blocks until the station is connected, up to `timeout`. Returns
+    /// whether it is.
+    pub fn wait_up_timeout(&self, timeout: Duration) -> bool {
+        let (up, changed) = &*self.up;
+        let up = up.lock().unwrap();
+        let (up, _) = changed.wait_timeout_while(up, timeout, |up| !*up).unwrap();
+        *up
+    }
+
+    fn set(&self, value: bool) {
+        let (up, changed) = &*self.up;
+        *up.lock().unwrap() = value;
+        changed.notify_all();
+    }
+}
+
+/// Watches the connection of `station` on its own thread, scanning for the
+/// best saved network with exponential backoff when the access point is lost.
+/// The link state and signal strength are sent to the display.
+pub fn supervise(station: Station, display: mpsc::SyncSender<DisplayMessage>) -> Result<Link> {
+    let Station {
+        mut wifi,
+        mut networks,
+    } = station;
+    let link = Link::new(is_connected(&wifi));
+
+    let supervised = link.clone();
+    thread::Builder::new().stack_size(8192).spawn(move || {
+        let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+        let mut shown: Option<WifiStatus> = None;
+
+        loop {
+            let status = if is_connected(&wifi) {
+                backoff.reset();
+                // No bars when the signal can't be read
+                WifiStatus::Connected(rssi().unwrap_or(-100.0))
+            } else {
+                WifiStatus::Offline
+            };
+
+            let changed = match (shown, status) {
+                (Some(WifiStatus::Connected(old)), WifiStatus::Connected(new)) => {
+                    (old - new).abs() >= RSSI_HYSTERESIS
+                }
+                (shown, status) => shown != Some(status),
+            };
+            if changed {
+                if display.send(DisplayMessage::WiFi(status)).is_err() {
+                    break;
+                }
+                shown = Some(status);
+            }
+            // After the display message, so it is drawn before anything the
+            // threads waiting for the link send
+            supervised.set(status != WifiStatus::Offline);
+
+            if status != WifiStatus::Offline {
+                thread::sleep(CHECK_INTERVAL);
+                continue;
+            }
+
+            let delay = backoff.next_delay();
+            warn!("Wifi disconnected, reconnecting");
+            match networks.join(&mut wifi, false) {
+                Ok(()) => continue,
+                Err(e) => error!("Error reconnecting to Wifi: {}", e),
+            }
+            info!("Next Wifi attempt in {}s", delay.as_secs());
+            thread::sleep(delay);
+        }
+    })?;
+
+    Ok(link)
+}
+
+/// Signal strength of the access point the station is connected to, in dBm
+fn rssi() -> Option<f32> {
+    let mut info: esp_idf_sys::wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    // Fails when not connected
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+    Some(info.rssi as f32)
+}
+
+/// Starts an open SoftAP for provisioning, clients get the device address
+/// (`provisioning::PORTAL_IP`) as gateway and DNS server.
+pub fn start_access_point(default_nvs: Arc<EspDefaultNvs>) -> Result<Box<EspWifi>, anyhow::Error> {
+    let netif_stack = Arc::new(EspNetifStack::new()?);
+    let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
+
+    let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: AP_SSID.into(),
+        channel: 1,
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    }))?;
+
+    wifi.wait_status_with_timeout(Duration::from_secs(20), |status| !status.is_transitional())
+        .map_err(|e| anyhow::anyhow!("Unexpected Wifi status: {:?}", e))?;
+
+    let status = wifi.get_status();
+
+    if let Status(ClientStatus::Stopped, ApStatus::Started(ApIpStatus::Done)) = status {
+        info!("Wifi access point {} started", AP_SSID);
     } else {
         bail!("Unexpected Wifi status: {:?}", status);
     }